@@ -0,0 +1,50 @@
+//! Compares the cost of dropping a `wait` future which has resolved with one which is still
+//! pending. A resolved future should not need to lock its shard again when it is dropped.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use waitmap::WaitMap;
+
+const ITERS: usize = 100_000;
+
+struct Noop;
+
+impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn time_drops<F>(futures: Vec<Pin<Box<F>>>) -> Duration {
+    let start = Instant::now();
+    drop(futures);
+    start.elapsed()
+}
+
+fn main() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    let keys: Vec<String> = (0..ITERS).map(|i| i.to_string()).collect();
+    let waker = Waker::from(Arc::new(Noop));
+    let mut cx = Context::from_waker(&waker);
+
+    for key in &keys {
+        map.insert(key.clone(), 0);
+    }
+    let mut resolved: Vec<_> = keys.iter().map(|key| Box::pin(map.wait(&key[..]))).collect();
+    for wait in &mut resolved {
+        assert!(wait.as_mut().poll(&mut cx).is_ready());
+    }
+    let resolved = time_drops(resolved);
+
+    keys.iter().for_each(|key| { map.take(&key[..]); });
+
+    let mut pending: Vec<_> = keys.iter().map(|key| Box::pin(map.wait(&key[..]))).collect();
+    for wait in &mut pending {
+        assert!(wait.as_mut().poll(&mut cx).is_pending());
+    }
+    let pending = time_drops(pending);
+
+    println!("dropping {} resolved waits: {:?}", ITERS, resolved);
+    println!("dropping {} pending waits:  {:?}", ITERS, pending);
+}
@@ -0,0 +1,131 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{Hash, BuildHasher};
+use std::marker::PhantomData;
+
+use dashmap::DashMap;
+
+use crate::WaitMap;
+use crate::semaphore::Semaphore;
+
+/// A builder for configuring a `WaitMap`.
+///
+/// Created by [`WaitMap::builder`](crate::WaitMap::builder). Callbacks are set on the built
+/// map itself, with methods such as [`WaitMap::on_insert`](crate::WaitMap::on_insert).
+/// ```
+/// # extern crate async_std;
+/// # extern crate waitmap;
+/// # use async_std::main;
+/// # use std::collections::hash_map::RandomState;
+/// # use waitmap::WaitMap;
+/// # #[async_std::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let map: WaitMap<String, i32> = WaitMap::builder()
+///     .capacity(64)
+///     .hasher(RandomState::new())
+///     .build();
+/// map.insert("Voltairine de Cleyre".to_string(), 0);
+/// # Ok(())
+/// # }
+/// ```
+pub struct WaitMapBuilder<K, V, S = RandomState> {
+    capacity: usize,
+    hasher: S,
+    max_waiters: Option<usize>,
+    max_in_flight: Option<usize>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Hash + Eq, V> WaitMapBuilder<K, V> {
+    pub(crate) fn new() -> WaitMapBuilder<K, V> {
+        WaitMapBuilder {
+            capacity: 0,
+            hasher: RandomState::default(),
+            max_waiters: None,
+            max_in_flight: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> WaitMapBuilder<K, V, S> {
+    /// Sets how many entries the map can hold without reallocating.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets the hasher the map uses to hash its keys.
+    pub fn hasher<T: BuildHasher + Clone>(self, hasher: T) -> WaitMapBuilder<K, V, T> {
+        WaitMapBuilder {
+            capacity: self.capacity,
+            hasher,
+            max_waiters: self.max_waiters,
+            max_in_flight: self.max_in_flight,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets how many tasks may wait on a single key at once.
+    ///
+    /// When another task starts waiting on a key which already has this many waiters, the
+    /// waiter which registered first is evicted: its `wait` evaluates to `None`, as if it had
+    /// been cancelled, while the key stays in the map. This bounds the memory used by very hot
+    /// keys by shedding their stalest waiters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn max_waiters_per_key(mut self, max: usize) -> Self {
+        assert!(max > 0, "a key must allow at least one waiter");
+        self.max_waiters = Some(max);
+        self
+    }
+
+    /// Sets how many references resolved by waits may be held at once, across the whole map.
+    ///
+    /// Once `max` of them are held, a wait whose key is filled does not resolve until one of
+    /// them is dropped, so a burst of fills cannot hand out an unbounded number of guards at
+    /// once. Every wait which resolves to a `Ref` or `RefMut` is limited, such as `wait`,
+    /// `wait_mut`, `wait_eq` and `wait_first`, and the waits built on them. References from
+    /// lookups which do not wait, such as `get`, `get_mut` and `poll_get`, are not. A task which
+    /// holds `max` references and waits for another never wakes up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::builder().max_in_flight(1).build();
+    /// map.insert("Gustav Landauer".to_string(), 1870);
+    /// map.insert("Erich Mühsam".to_string(), 1878);
+    ///
+    /// let landauer = map.wait("Gustav Landauer").await.unwrap();
+    /// let muhsam = map.wait("Erich Mühsam").timeout(Duration::from_millis(20)).await;
+    /// assert!(muhsam.is_err());
+    ///
+    /// drop(landauer);
+    /// assert_eq!(map.wait("Erich Mühsam").await.unwrap().value(), &1878);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        assert!(max > 0, "at least one reference must be allowed");
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Builds the map.
+    pub fn build(self) -> WaitMap<K, V, S> {
+        let inner = DashMap::with_capacity_and_hasher(self.capacity, self.hasher);
+        let mut map = WaitMap::from_dashmap(inner);
+        map.max_waiters = self.max_waiters;
+        map.in_flight = self.max_in_flight.map(Semaphore::new);
+        map
+    }
+}
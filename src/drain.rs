@@ -0,0 +1,71 @@
+use std::hash::{Hash, BuildHasher};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::vec;
+
+use futures_core::Stream;
+
+use crate::WaitMap;
+use crate::WaitEntry::*;
+
+pub struct DrainStream<'a, K, V, S> {
+    map: &'a WaitMap<K, V, S>,
+    shard: usize,
+    drained: vec::IntoIter<(K, V)>,
+    yielded: bool,
+}
+
+impl<'a, K, V, S> DrainStream<'a, K, V, S> {
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>) -> Self {
+        DrainStream { map, shard: 0, drained: Vec::new().into_iter(), yielded: true }
+    }
+}
+
+impl<'a, K, V, S> Unpin for DrainStream<'a, K, V, S> { }
+
+impl<'a, K, V, S> Stream for DrainStream<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    type Item = (K, V);
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<(K, V)>> {
+        loop {
+            if let Some(pair) = self.drained.next() {
+                return Poll::Ready(Some(pair));
+            }
+
+            let shards = self.map.map.shards();
+            if self.shard == shards.len() {
+                return Poll::Ready(None);
+            }
+
+            // yield to the executor before locking each shard after the first
+            if !self.yielded {
+                self.yielded = true;
+                ctx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let entries: Vec<_> = shards[self.shard].write().drain().collect();
+            let mut drained = Vec::with_capacity(entries.len());
+            for (key, entry) in entries {
+                match entry.into_inner() {
+                    Filled(value, _) => {
+                        self.map.count_removal(&key);
+                        self.map.hooks.removed(&key, &value);
+                        drained.push((key, value));
+                    }
+                    Waiting(wakers)  => {
+                        wakers.wake_removed();
+                        self.map.hooks.cancelled(&key);
+                    }
+                }
+            }
+
+            self.shard += 1;
+            self.yielded = false;
+            self.drained = drained.into_iter();
+        }
+    }
+}
@@ -0,0 +1,440 @@
+use std::hash::{Hash, BuildHasher};
+use std::mem;
+
+use dashmap::mapref::entry as dash;
+
+use crate::{RefMut, WaitMap, WaitEntry};
+use crate::WaitEntry::*;
+use crate::locked::{GuardMut, LockedKey};
+use crate::waker_set::WakerSet;
+
+/// A view into a single key of a `WaitMap`, created by [`WaitMap::entry`](crate::WaitMap::entry).
+///
+/// An entry is occupied if the key is filled. If the key is missing, or waiting to be filled,
+/// the entry is vacant; filling a vacant entry wakes up any tasks waiting on it.
+pub enum Entry<'a, K, V, S> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher + Clone> Entry<'a, K, V, S> {
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: K) -> Self {
+        match map.map.entry(key) {
+            dash::Entry::Occupied(inner)    => Entry::occupied(map, Occupied::Map(inner)),
+            dash::Entry::Vacant(inner)      => {
+                Entry::Vacant(VacantEntry { map, inner: Slot::Vacant(Vacant::Map(inner)) })
+            }
+        }
+    }
+
+    // Gets the entry for a key of a shard which was locked by hand.
+    pub(crate) fn locked(map: &'a WaitMap<K, V, S>, key: LockedKey<'a, K, V, S>) -> Self {
+        match key.get() {
+            Some(_) => Entry::occupied(map, Occupied::Locked(key)),
+            None    => Entry::Vacant(VacantEntry { map, inner: Slot::Vacant(Vacant::Locked(key)) }),
+        }
+    }
+
+    fn occupied(map: &'a WaitMap<K, V, S>, inner: Occupied<'a, K, V, S>) -> Self {
+        match inner.get() {
+            Filled(..)  => Entry::Occupied(OccupiedEntry { map, inner }),
+            Waiting(_)  => Entry::Vacant(VacantEntry { map, inner: Slot::Waiting(inner) }),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry)  => entry.key(),
+            Entry::Vacant(entry)    => entry.key(),
+        }
+    }
+
+    /// Applies a function to the value if the entry is occupied.
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
+        match self {
+            Entry::Occupied(mut entry)  => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry)        => Entry::Vacant(entry),
+        }
+    }
+
+    /// Applies a function to the value if the entry is occupied, or fills the entry with the
+    /// result of `fill` and then applies the function if tasks are waiting on it.
+    ///
+    /// Filling the entry wakes the tasks waiting on it. An entry whose key is missing from the
+    /// map, rather than waiting to be filled, is left vacant, since no one is waiting for it.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<&str>> = WaitMap::new();
+    /// let wait_fut = map.wait("Errico Malatesta");
+    ///
+    /// map.entry("Errico Malatesta".to_string())
+    ///     .and_modify_or_fill(|books| books.push("Anarchy"), Vec::new);
+    /// map.entry("Carlo Cafiero".to_string())
+    ///     .and_modify_or_fill(|books| books.push("Compendium of Capital"), Vec::new);
+    ///
+    /// assert_eq!(wait_fut.await.unwrap().value(), &["Anarchy"]);
+    /// assert!(map.get("Carlo Cafiero").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn and_modify_or_fill(self, modify: impl FnOnce(&mut V), fill: impl FnOnce() -> V)
+        -> Self
+    {
+        match self {
+            Entry::Vacant(entry) if entry.is_waiting() => {
+                let mut entry = entry.fill(fill());
+                modify(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            entry                                       => entry.and_modify(modify),
+        }
+    }
+
+    /// Removes the entry if it is occupied and its key-value pair matches a predicate,
+    /// returning the removed value.
+    ///
+    /// Vacant entries are never removed, so this cannot cancel pending waits.
+    pub fn and_remove_if(self, pred: impl FnOnce(&K, &V) -> bool) -> Option<V> {
+        match self {
+            Entry::Occupied(entry) if pred(entry.key(), entry.get()) => Some(entry.remove()),
+            _                                                       => None,
+        }
+    }
+
+    /// Returns a reference to the value, filling the entry with `value` if it is vacant.
+    pub fn or_insert(self, value: V) -> RefMut<'a, K, V, S> {
+        self.or_insert_with(|| value)
+    }
+
+    /// Returns a reference to the value, filling the entry with the result of `value` if it is
+    /// vacant.
+    pub fn or_insert_with(self, value: impl FnOnce() -> V) -> RefMut<'a, K, V, S> {
+        self.or_insert_with_key(|_| value())
+    }
+
+    /// Returns a reference to the value, filling the entry with the result of `value` if it is
+    /// vacant.
+    ///
+    /// Like `or_insert_with`, except that `value` is passed the entry's key, for values which
+    /// depend on it. Filling a key which is waiting to be filled wakes the tasks waiting on it.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, String> = WaitMap::new();
+    /// let wait_fut = map.wait("Ida Mett");
+    ///
+    /// map.entry("Ida Mett".to_string()).or_insert_with_key(|key| format!("{}: Kronstadt", key));
+    /// assert_eq!(wait_fut.await.unwrap().value(), "Ida Mett: Kronstadt");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn or_insert_with_key(self, value: impl FnOnce(&K) -> V) -> RefMut<'a, K, V, S> {
+        match self {
+            Entry::Occupied(entry)  => entry.into_ref(),
+            Entry::Vacant(entry)    => {
+                let value = value(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Returns a reference to the value, filling the entry with the default value if it is
+    /// vacant.
+    pub fn or_default(self) -> RefMut<'a, K, V, S> where V: Default {
+        self.or_insert_with(V::default)
+    }
+}
+
+/// A view into a filled key of a `WaitMap`.
+pub struct OccupiedEntry<'a, K, V, S> {
+    map: &'a WaitMap<K, V, S>,
+    inner: Occupied<'a, K, V, S>,
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher + Clone> OccupiedEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    pub fn get(&self) -> &V {
+        match self.inner.get() {
+            Filled(value, _) => value,
+            _                => panic!()
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        match self.inner.get_mut() {
+            Filled(value, _) => value,
+            _                => panic!()
+        }
+    }
+
+    /// Replaces the value, returning the old value.
+    pub fn insert(&mut self, value: V) -> V {
+        self.map.hooks.inserted(self.inner.key(), &value);
+        self.map.listeners.filled(self.inner.key(), &value);
+        match mem::replace(self.inner.get_mut(), Filled(value, None)) {
+            Filled(value, _) => value,
+            _                => panic!()
+        }
+    }
+
+    pub fn into_ref(self) -> RefMut<'a, K, V, S> {
+        self.map.make_ref_mut(self.inner.into_ref())
+    }
+
+    /// Removes the entry from the map, returning its value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes the entry from the map, returning its value.
+    ///
+    /// This is the same as `remove`, named to match [`WaitMap::take`](crate::WaitMap::take):
+    /// an occupied entry is always filled, so removing it never cancels any waits.
+    pub fn take(self) -> V {
+        self.remove()
+    }
+
+    /// Removes the entry from the map, returning its key-value pair.
+    pub fn remove_entry(self) -> (K, V) {
+        let map = self.map;
+        match self.inner.remove_entry() {
+            (key, Filled(value, _)) => {
+                map.count_removal(&key);
+                map.hooks.removed(&key, &value);
+                (key, value)
+            }
+            _                       => panic!()
+        }
+    }
+
+    /// Replaces or removes the entry, depending on what `f` returns for its key and value.
+    ///
+    /// If `f` returns `Some`, the value is replaced and the entry stays occupied. If it returns
+    /// `None`, the entry is removed and a vacant entry is returned. Because an occupied entry
+    /// is always filled, removing it never disturbs any waits. The `on_remove` hook is not run
+    /// when the entry is removed, since the old value has been passed to `f`.
+    ///
+    /// The shard's lock is released after removing the entry and taken again for the vacant
+    /// entry, so if another thread fills the key in between, the returned entry is occupied.
+    /// An entry from [`WaitMap::try_entry`](crate::WaitMap::try_entry) keeps its lock instead.
+    /// If `f` panics, the key is left waiting to be filled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::{Entry, WaitMap};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Buenaventura Durruti".to_string(), 1);
+    ///
+    /// let entry = match map.entry("Buenaventura Durruti".to_string()) {
+    ///     Entry::Occupied(entry)  => entry,
+    ///     Entry::Vacant(_)        => unreachable!(),
+    /// };
+    /// let entry = entry.replace_entry_with(|_, count| {
+    ///     if count > 1 { Some(count - 1) } else { None }
+    /// });
+    /// assert!(matches!(entry, Entry::Vacant(_)));
+    /// drop(entry);
+    /// assert!(map.get("Buenaventura Durruti").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replace_entry_with(mut self, f: impl FnOnce(&K, V) -> Option<V>) -> Entry<'a, K, V, S> {
+        // NB: the entry holds a placeholder while `f` runs, but since the shard stays locked no
+        // task can register itself as waiting on it.
+        let old = match mem::replace(self.inner.get_mut(), Waiting(WakerSet::new())) {
+            Filled(value, _) => value,
+            _                => panic!()
+        };
+        match (f(self.inner.key(), old), self.inner) {
+            (Some(value), mut inner)            => {
+                self.map.hooks.inserted(inner.key(), &value);
+                self.map.listeners.filled(inner.key(), &value);
+                *inner.get_mut() = Filled(value, None);
+                Entry::Occupied(OccupiedEntry { map: self.map, inner })
+            }
+            (None, Occupied::Map(inner))        => {
+                let (key, _) = inner.remove_entry();
+                self.map.count_removal(&key);
+                Entry::new(self.map, key)
+            }
+            (None, Occupied::Locked(mut inner)) => {
+                inner.take();
+                self.map.count_removal(inner.key());
+                Entry::locked(self.map, inner)
+            }
+        }
+    }
+}
+
+/// A view into a key of a `WaitMap` which is missing or waiting to be filled.
+pub struct VacantEntry<'a, K, V, S> {
+    map: &'a WaitMap<K, V, S>,
+    inner: Slot<'a, K, V, S>,
+}
+
+enum Slot<'a, K, V, S> {
+    Vacant(Vacant<'a, K, V, S>),
+    Waiting(Occupied<'a, K, V, S>),
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher + Clone> VacantEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        match &self.inner {
+            Slot::Vacant(inner)     => inner.key(),
+            Slot::Waiting(inner)    => inner.key(),
+        }
+    }
+
+    pub fn into_key(self) -> K {
+        match self.inner {
+            Slot::Vacant(inner)     => inner.into_key(),
+            Slot::Waiting(inner)    => inner.into_key(),
+        }
+    }
+
+    /// Returns `true` if the key is waiting to be filled, rather than missing from the map.
+    pub fn is_waiting(&self) -> bool {
+        matches!(self.inner, Slot::Waiting(_))
+    }
+
+    // Fills a key which is waiting to be filled, waking the tasks waiting on it.
+    fn fill(self, value: V) -> OccupiedEntry<'a, K, V, S> {
+        self.map.hooks.inserted(self.key(), &value);
+        self.map.listeners.filled(self.key(), &value);
+        self.map.count_fills(1);
+        match self.inner {
+            Slot::Waiting(mut inner)    => {
+                if let Waiting(wakers) = inner.insert(Filled(value, None)) {
+                    wakers.wake();
+                }
+                OccupiedEntry { map: self.map, inner }
+            }
+            Slot::Vacant(_)             => panic!()
+        }
+    }
+
+    /// Fills the entry with a value, waking any tasks waiting on it, and returns a reference
+    /// to the value.
+    ///
+    /// Waiting tasks are woken while the returned reference still holds its lock; they can
+    /// only get at the value once it has been dropped.
+    pub fn insert(self, value: V) -> RefMut<'a, K, V, S> {
+        self.map.hooks.inserted(self.key(), &value);
+        self.map.listeners.filled(self.key(), &value);
+        self.map.count_fills(1);
+        let inner = match self.inner {
+            Slot::Vacant(inner)         => inner.insert(Filled(value, None)),
+            Slot::Waiting(mut inner)    => {
+                if let Waiting(wakers) = inner.insert(Filled(value, None)) {
+                    wakers.wake();
+                }
+                inner.into_ref()
+            }
+        };
+        self.map.make_ref_mut(inner)
+    }
+}
+
+// The dashmap entries a `WaitMap` entry wraps, or a key of a shard which was locked by hand,
+// since dashmap can't build an entry from a lock taken outside of it.
+enum Occupied<'a, K, V, S> {
+    Map(dash::OccupiedEntry<'a, K, WaitEntry<V>, S>),
+    Locked(LockedKey<'a, K, V, S>),
+}
+
+enum Vacant<'a, K, V, S> {
+    Map(dash::VacantEntry<'a, K, WaitEntry<V>, S>),
+    Locked(LockedKey<'a, K, V, S>),
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher + Clone> Occupied<'a, K, V, S> {
+    fn key(&self) -> &K {
+        match self {
+            Occupied::Map(inner)    => inner.key(),
+            Occupied::Locked(inner) => inner.key(),
+        }
+    }
+
+    fn get(&self) -> &WaitEntry<V> {
+        match self {
+            Occupied::Map(inner)    => inner.get(),
+            Occupied::Locked(inner) => inner.get().unwrap(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut WaitEntry<V> {
+        match self {
+            Occupied::Map(inner)    => inner.get_mut(),
+            Occupied::Locked(inner) => inner.get_mut().unwrap(),
+        }
+    }
+
+    fn insert(&mut self, entry: WaitEntry<V>) -> WaitEntry<V> {
+        mem::replace(self.get_mut(), entry)
+    }
+
+    fn into_ref(self) -> GuardMut<'a, K, V, S> {
+        match self {
+            Occupied::Map(inner)    => inner.into_ref().into(),
+            Occupied::Locked(inner) => inner.into(),
+        }
+    }
+
+    fn remove_entry(self) -> (K, WaitEntry<V>) {
+        match self {
+            Occupied::Map(inner)    => inner.remove_entry(),
+            Occupied::Locked(inner) => inner.remove_entry().unwrap(),
+        }
+    }
+
+    fn into_key(self) -> K {
+        match self {
+            Occupied::Map(inner)    => inner.into_key(),
+            Occupied::Locked(inner) => inner.into_key(),
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher + Clone> Vacant<'a, K, V, S> {
+    fn key(&self) -> &K {
+        match self {
+            Vacant::Map(inner)      => inner.key(),
+            Vacant::Locked(inner)   => inner.key(),
+        }
+    }
+
+    fn into_key(self) -> K {
+        match self {
+            Vacant::Map(inner)      => inner.into_key(),
+            Vacant::Locked(inner)   => inner.into_key(),
+        }
+    }
+
+    fn insert(self, entry: WaitEntry<V>) -> GuardMut<'a, K, V, S> {
+        match self {
+            Vacant::Map(inner)          => inner.insert(entry).into(),
+            Vacant::Locked(mut inner)   => {
+                inner.insert(entry);
+                inner.into()
+            }
+        }
+    }
+}
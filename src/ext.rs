@@ -0,0 +1,46 @@
+use std::hash::{Hash, BuildHasher};
+use std::sync::Arc;
+
+use crate::WaitMap;
+use crate::wait::WaitOwned;
+
+/// Methods for a `WaitMap` shared through an `Arc`.
+///
+/// These return futures which hold their own clone of the `Arc`, so unlike the futures
+/// returned by `WaitMap` itself they do not borrow the map and can be spawned onto other tasks.
+pub trait WaitMapExt<K: Hash + Eq, V, S: BuildHasher + Clone> {
+    /// Waits for a key to be filled, resolving to a clone of its value.
+    ///
+    /// Resolves to `None` if the key is cancelled or removed before it is filled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use waitmap::{WaitMap, WaitMapExt};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let handle = task::spawn(map.spawn_wait("Ida Mett".to_string()));
+    /// map.insert("Ida Mett".to_string(), 1901);
+    /// assert_eq!(handle.await, Some(1901));
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn spawn_wait(&self, key: K) -> WaitOwned<K, V, S>
+    where
+        K: Clone,
+        V: Clone;
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> WaitMapExt<K, V, S> for Arc<WaitMap<K, V, S>> {
+    fn spawn_wait(&self, key: K) -> WaitOwned<K, V, S>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let (_, reservation) = self.reserve_key(key.clone());
+        WaitOwned::new(self.clone(), key, reservation)
+    }
+}
@@ -0,0 +1,43 @@
+use std::future::Future;
+use std::hash::{Hash, BuildHasher};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::WaitMap;
+
+pub struct ExtendStream<'a, K, V, S, St> {
+    map: &'a WaitMap<K, V, S>,
+    stream: Pin<Box<St>>,
+    inserted: usize,
+}
+
+impl<'a, K, V, S, St> ExtendStream<'a, K, V, S, St> {
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, stream: St) -> Self {
+        ExtendStream { map, stream: Box::pin(stream), inserted: 0 }
+    }
+}
+
+impl<'a, K, V, S, St> Future for ExtendStream<'a, K, V, S, St> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+    St: Stream<Item = (K, V)>,
+{
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<usize> {
+        match self.stream.as_mut().poll_next(ctx) {
+            Poll::Ready(Some((key, value))) => {
+                self.map.insert(key, value);
+                self.inserted += 1;
+                // yield to the executor between items, so a stream which is always ready
+                // does not stall other tasks
+                ctx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(None)               => Poll::Ready(self.inserted),
+            Poll::Pending                   => Poll::Pending,
+        }
+    }
+}
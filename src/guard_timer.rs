@@ -0,0 +1,25 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) struct GuardTimer {
+    created: Instant,
+    limit: Option<Duration>,
+}
+
+impl GuardTimer {
+    pub(crate) fn start(limit: Option<Duration>) -> GuardTimer {
+        GuardTimer { created: Instant::now(), limit }
+    }
+}
+
+impl Drop for GuardTimer {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit {
+            let held = self.created.elapsed();
+            // don't double panic if the guard is being dropped while unwinding
+            if cfg!(debug_assertions) && held > limit && !thread::panicking() {
+                panic!("waitmap guard held for {:?}, longer than the limit of {:?}", held, limit);
+            }
+        }
+    }
+}
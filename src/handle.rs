@@ -0,0 +1,124 @@
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Waker;
+
+/// A handle which cancels a single wait future.
+///
+/// Created by [`WaitMap::wait_with_handle`](crate::WaitMap::wait_with_handle), or later on by
+/// [`Wait::cancel_handle`](crate::Wait::cancel_handle). Cancelling through the handle only
+/// affects the future it was created with: other tasks waiting on the same key keep waiting,
+/// and the key stays in the map.
+#[derive(Clone)]
+pub struct WaitHandle {
+    state: Arc<CancelState>,
+}
+
+impl WaitHandle {
+    pub(crate) fn new() -> (WaitHandle, Arc<CancelState>) {
+        let state = Arc::new(CancelState {
+            cancelled: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        });
+        (WaitHandle { state: state.clone() }, state)
+    }
+
+    // Makes a handle to the state of a wait which can already be cancelled.
+    pub(crate) fn for_state(state: &Arc<CancelState>) -> WaitHandle {
+        WaitHandle { state: state.clone() }
+    }
+
+    /// Cancels the wait future, so that it evaluates to `None`.
+    ///
+    /// This has no effect if the future has already completed.
+    pub fn cancel(&self) {
+        self.state.cancel();
+    }
+
+    /// Returns `true` if `cancel` has been called on this handle or one of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A token which cancels every wait future created with it.
+///
+/// Passed to [`WaitMap::wait_with_token`](crate::WaitMap::wait_with_token). Unlike a
+/// [`WaitHandle`], one token can be shared by any number of waits, on any number of keys and
+/// maps, so that a whole group of tasks can stop waiting at once. Clones of a token share its
+/// state, and once a token has been cancelled, waits created with it evaluate to `None`
+/// straight away.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    waits: Mutex<Vec<Weak<CancelState>>>,
+}
+
+impl CancelToken {
+    /// Creates a new token which has not been cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Cancels every wait future created with this token, so that they evaluate to `None`.
+    ///
+    /// This has no effect on futures which have already completed.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        let waits = mem::take(&mut *self.state.waits.lock().unwrap());
+        for wait in waits.iter().filter_map(Weak::upgrade) {
+            wait.cancel();
+        }
+    }
+
+    /// Returns `true` if `cancel` has been called on this token or one of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Ties the cancellation of a single wait to this token.
+    pub(crate) fn attach(&self, wait: &Arc<CancelState>) {
+        let mut waits = self.state.waits.lock().unwrap();
+        // NB: the flag is checked under the lock, so a concurrent `cancel` either sees this
+        // wait in the list or has already set the flag.
+        if self.is_cancelled() {
+            wait.cancel();
+        } else {
+            // forget the waits which have been dropped, so the list does not keep growing
+            waits.retain(|wait| wait.strong_count() > 0);
+            waits.push(Arc::downgrade(wait));
+        }
+    }
+}
+
+pub(crate) struct CancelState {
+    cancelled: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl CancelState {
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    // Stores the waker to be woken on cancellation, returning `true` if already cancelled.
+    pub(crate) fn register(&self, waker: &Waker) -> bool {
+        // NB: the waker is stored before the flag is checked, so a concurrent `cancel` either
+        // sees the waker or is seen by the check.
+        let mut slot = self.waker.lock().unwrap();
+        match &*slot {
+            Some(old) if old.will_wake(waker)   => {}
+            _                                   => *slot = Some(waker.clone()),
+        }
+        drop(slot);
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
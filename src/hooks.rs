@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+type PairHook<K, V> = Box<dyn Fn(&K, &V) + Send + Sync>;
+type KeyHook<K> = Box<dyn Fn(&K) + Send + Sync>;
+type WaitHook = Box<dyn Fn(Duration, WaitOutcome) + Send + Sync>;
+
+/// How a wait future which had to park was resolved.
+///
+/// Passed to the callback set by [`WaitMap::on_wait_resolved`](crate::WaitMap::on_wait_resolved).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The key was filled.
+    Filled {
+        /// How many tasks were waiting on the key when it was filled, including this one.
+        waiters: usize,
+    },
+    /// The key was cancelled or removed, or the wait itself was cancelled.
+    Cancelled,
+}
+
+pub(crate) struct Hooks<K, V> {
+    on_insert: Option<PairHook<K, V>>,
+    on_remove: Option<PairHook<K, V>>,
+    on_cancel: Option<KeyHook<K>>,
+    on_wait_resolved: Option<WaitHook>,
+}
+
+impl<K, V> Hooks<K, V> {
+    pub(crate) fn new() -> Hooks<K, V> {
+        Hooks { on_insert: None, on_remove: None, on_cancel: None, on_wait_resolved: None }
+    }
+
+    pub(crate) fn set_on_insert(&mut self, hook: impl Fn(&K, &V) + Send + Sync + 'static) {
+        self.on_insert = Some(Box::new(hook));
+    }
+
+    pub(crate) fn set_on_remove(&mut self, hook: impl Fn(&K, &V) + Send + Sync + 'static) {
+        self.on_remove = Some(Box::new(hook));
+    }
+
+    pub(crate) fn set_on_cancel(&mut self, hook: impl Fn(&K) + Send + Sync + 'static) {
+        self.on_cancel = Some(Box::new(hook));
+    }
+
+    pub(crate) fn set_on_wait_resolved(
+        &mut self,
+        hook: impl Fn(Duration, WaitOutcome) + Send + Sync + 'static,
+    ) {
+        self.on_wait_resolved = Some(Box::new(hook));
+    }
+
+    pub(crate) fn inserted(&self, key: &K, value: &V) {
+        if let Some(hook) = &self.on_insert { hook(key, value) }
+    }
+
+    pub(crate) fn removed(&self, key: &K, value: &V) {
+        if let Some(hook) = &self.on_remove { hook(key, value) }
+    }
+
+    pub(crate) fn cancelled(&self, key: &K) {
+        if let Some(hook) = &self.on_cancel { hook(key) }
+    }
+
+    pub(crate) fn times_waits(&self) -> bool {
+        self.on_wait_resolved.is_some()
+    }
+
+    pub(crate) fn wait_resolved(&self, parked: Duration, outcome: WaitOutcome) {
+        if let Some(hook) = &self.on_wait_resolved { hook(parked, outcome) }
+    }
+}
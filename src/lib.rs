@@ -48,187 +48,3073 @@
 //! # }
 //! ```
 
+#[cfg(feature = "guard-timing")]
+mod guard_timer;
+mod builder;
+mod drain;
+mod entry;
+mod ext;
+mod extend;
+mod handle;
+mod hooks;
+mod listen;
+mod locked;
+#[cfg(feature = "rayon")]
+mod rayon;
+mod read_only;
+mod semaphore;
+mod shard;
+mod shared;
+mod timeout;
 mod wait;
 mod waker_set;
+mod watch;
 
 use std::borrow::Borrow;
 use std::collections::hash_map::RandomState;
-use std::future::Future;
+use std::fmt;
+use std::future::{poll_fn, Future};
 use std::hash::{Hash, BuildHasher};
 use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, SharedValue};
+use futures_core::Stream;
+use futures_timer::Delay;
+use futures_core::future::FusedFuture;
 use dashmap::mapref::entry::Entry::*;
-use dashmap::mapref::one;
+use dashmap::mapref::{multiple, one};
+
+pub use builder::WaitMapBuilder;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use ext::WaitMapExt;
+pub use handle::{CancelToken, WaitHandle};
+pub use hooks::WaitOutcome;
+#[cfg(feature = "rayon")]
+pub use crate::rayon::ParIter;
+pub use read_only::ReadOnlyWaitMap;
+pub use shard::ShardView;
+pub use shared::SharedWaitMap;
+pub use wait::{RemoveOutcome, Wait, WaitEq, WaitFirst, WaitMutOrDefault, WaitMatching, WaitOwned,
+               WaitOwnedTake, WaitRemoved, WaitTake, WaitTakeDetailed, WaitWeak};
+pub use watch::Watcher;
 
 use WaitEntry::*;
-use wait::{Wait, WaitMut};
+use drain::DrainStream;
+use extend::ExtendStream;
+use hooks::Hooks;
+use listen::Listeners;
+use locked::{GuardMut, LockedKey};
+use semaphore::{HoldPermit, Permit, Semaphore};
+use shard::extract_matching;
+use timeout::{timeout, Until};
+#[cfg(feature = "guard-timing")]
+use guard_timer::GuardTimer;
+use wait::{Refill, WaitMut};
 use waker_set::WakerSet;
 
 /// An asynchronous concurrent hashmap.
 pub struct WaitMap<K, V, S = RandomState> {
     map: DashMap<K, WaitEntry<V>, S>,
+    hooks: Hooks<K, V>,
+    listeners: Listeners<K, V>,
+    // the tasks waiting for filled keys to be removed, see `wait_removed`
+    removals: Listeners<K, ()>,
+    closed: AtomicBool,
+    max_waiters: Option<usize>,
+    // the number of filled keys, so that `len` does not have to lock every shard
+    filled: AtomicUsize,
+    in_flight: Option<Semaphore>,
+    #[cfg(feature = "guard-timing")]
+    guard_limit: Option<Duration>,
 }
 
 impl<K: Hash + Eq, V> WaitMap<K, V> {
     /// Make a new `WaitMap` using the default hasher.
     pub fn new() -> WaitMap<K, V> {
-        WaitMap { map: DashMap::with_hasher(RandomState::default()) }
+        WaitMap::from_dashmap(DashMap::with_hasher(RandomState::default()))
+    }
+
+    /// Make a builder for configuring a new `WaitMap`.
+    ///
+    /// The builder uses the default hasher unless another one is set with
+    /// [`WaitMapBuilder::hasher`](crate::WaitMapBuilder::hasher).
+    pub fn builder() -> WaitMapBuilder<K, V> {
+        WaitMapBuilder::new()
+    }
+}
+
+impl<K: Hash + Eq, V> Default for WaitMap<K, V> {
+    fn default() -> WaitMap<K, V> {
+        WaitMap::new()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> WaitMap<K, V, S> {
+    /// Make a new `WaitMap` using a custom hasher.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// use std::collections::hash_map::RandomState;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<i32, String> = WaitMap::with_hasher(RandomState::new());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_hasher(hasher: S) -> WaitMap<K, V, S> {
+        WaitMap::from_dashmap(DashMap::with_hasher(hasher))
+    }
+
+    fn from_dashmap(map: DashMap<K, WaitEntry<V>, S>) -> WaitMap<K, V, S> {
+        WaitMap {
+            map,
+            hooks: Hooks::new(),
+            listeners: Listeners::new(),
+            removals: Listeners::new(),
+            closed: AtomicBool::new(false),
+            max_waiters: None,
+            filled: AtomicUsize::new(0),
+            in_flight: None,
+            #[cfg(feature = "guard-timing")]
+            guard_limit: None,
+        }
+    }
+
+    // Puts a placeholder for the key into the map if it is missing, unless the map has been
+    // shut down, and hands out a reservation on it which keeps it in the map until the wait
+    // holding the reservation has been polled or dropped. Returns `true` if a placeholder was
+    // put into the map.
+    pub(crate) fn reserve_key(&self, key: K) -> (bool, Option<Arc<()>>) {
+        if self.is_closed() { return (false, None); }
+        // NB: checked under a read lock first, like `poll_ref`, so that waiting on a key which
+        // is already filled does not take the write lock of its shard.
+        if matches!(self.map.get(&key).as_deref(), Some(Filled(..))) { return (false, None); }
+        match self.map.entry(key) {
+            Occupied(mut entry) => match entry.get_mut() {
+                Waiting(wakers) => (false, Some(wakers.reserve_waiter())),
+                Filled(..)      => (false, None),
+            }
+            Vacant(slot)        => {
+                let mut wakers = WakerSet::new();
+                let reservation = wakers.reserve_waiter();
+                slot.insert(Waiting(wakers));
+                (true, Some(reservation))
+            }
+        }
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned.
+    ///
+    /// If there are any pending `wait` calls for this key, they are woken up.
+    ///
+    /// If the map did have this key present, the value is updated and the old value is returned.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, sync::Arc, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let insert_fut = async { map.insert("hi".to_string(), 0) };
+    /// let wait_fut = map.wait("hi");
+    ///
+    /// let (insert_res, wait_res) = insert_fut.join(wait_fut).await;
+    /// assert!(insert_res.is_none());
+    /// assert!(wait_res.is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let (old, wakers) = self.insert_deferred(key, value);
+        if let Some(wakers) = wakers {
+            wakers.wake();
+        }
+        old
+    }
+
+    // Inserts a key-value pair like `insert`, but returns the key's waiters instead of waking
+    // them, so that bulk inserts can wake them all at once.
+    fn insert_deferred(&self, key: K, value: V) -> (Option<V>, Option<WakerSet>) {
+        self.hooks.inserted(&key, &value);
+        let entry = self.map.entry(key);
+        self.listeners.filled(entry.key(), &value);
+        match entry {
+            Occupied(mut entry)  => {
+                match mem::replace(entry.get_mut(), Filled(value, None)) {
+                    Waiting(wakers)          => {
+                        self.count_fills(1);
+                        (None, Some(wakers))
+                    }
+                    Filled(value, held_back) => (Some(value), held_back.map(|wakers| *wakers)),
+                }
+            }
+            Vacant(slot)     => {
+                self.count_fills(1);
+                slot.insert(Filled(value, None));
+                (None, None)
+            }
+        }
+    }
+
+    /// Inserts a key-value pair into the map, returning a mutable reference to the value and the
+    /// number of waiting tasks which were woken up.
+    ///
+    /// If the key was already filled, its old value is dropped. The waiting tasks are woken
+    /// while the returned guard is still held, so they will only observe the value once it is
+    /// dropped. Only tasks which have polled their `wait` at least once are counted.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<&str>> = WaitMap::new();
+    ///
+    /// let (mut books, woken) = map.fill("Peter Kropotkin".to_string(), vec!["Mutual Aid"]);
+    /// books.push("The Conquest of Bread");
+    /// assert_eq!(woken, 0);
+    /// drop(books);
+    ///
+    /// assert_eq!(map.get("Peter Kropotkin").unwrap().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn fill(&self, key: K, value: V) -> (RefMut<'_, K, V, S>, usize) {
+        self.hooks.inserted(&key, &value);
+        let mut woken = 0;
+        let entry = self.map.entry(key);
+        self.listeners.filled(entry.key(), &value);
+        let inner = match entry {
+            Occupied(mut entry)  => {
+                if let Waiting(wakers) = mem::replace(entry.get_mut(), Filled(value, None)) {
+                    self.count_fills(1);
+                    woken = wakers.len();
+                    wakers.wake();
+                }
+                entry.into_ref()
+            }
+            Vacant(slot)     => {
+                self.count_fills(1);
+                slot.insert(Filled(value, None))
+            }
+        };
+        (self.make_ref_mut(inner), woken)
+    }
+
+    /// Inserts a key-value pair into the map, waking at most `n` of its waiting tasks.
+    ///
+    /// Returns the old value if the key was already filled, along with the number of tasks
+    /// which were woken. The tasks which have waited longest are woken first. The rest stay
+    /// parked until the key is inserted again, or until the map's waits are cancelled by
+    /// `cancel_all` or a shutdown, and then see whatever value the key holds once they poll.
+    /// If the key is removed instead, they evaluate to `None`. Tasks which start waiting after
+    /// the key was filled are not held back, and inserting the key again with `insert_wake_n`
+    /// only wakes `n` of the tasks still held back.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let insert_fut = async { map.insert_wake_n("Voltairine de Cleyre".to_string(), 1866, 1) };
+    /// let wait_fut = map.wait_entry("Voltairine de Cleyre");
+    ///
+    /// let ((old, _), pair) = insert_fut.join(wait_fut).await;
+    /// assert_eq!(old, None);
+    /// assert_eq!(pair, Some(("Voltairine de Cleyre".to_string(), 1866)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_wake_n(&self, key: K, value: V, n: usize) -> (Option<V>, usize) {
+        self.hooks.inserted(&key, &value);
+        let entry = self.map.entry(key);
+        self.listeners.filled(entry.key(), &value);
+        let (old, woken) = match entry {
+            Occupied(mut entry)  => {
+                let (old, wakers) = match mem::replace(entry.get_mut(), Filled(value, None)) {
+                    Waiting(wakers)          => {
+                        self.count_fills(1);
+                        (None, Some(wakers))
+                    }
+                    Filled(value, held_back) => (Some(value), held_back.map(|wakers| *wakers)),
+                };
+                let woken = match wakers {
+                    Some(mut wakers) => {
+                        // a closed map cancels no more waits, so nothing is held back
+                        let n = if self.is_closed() { usize::MAX } else { n };
+                        let woken = wakers.take_oldest(n);
+                        // NB: the rest stay with the key, so that replacing or removing its
+                        // value wakes them like any other waiters of the key.
+                        if wakers.len() > 0 {
+                            if let Filled(_, held_back) = entry.get_mut() {
+                                *held_back = Some(Box::new(wakers));
+                            }
+                        }
+                        woken
+                    }
+                    None             => vec![],
+                };
+                (old, woken)
+            }
+            Vacant(slot)     => {
+                self.count_fills(1);
+                slot.insert(Filled(value, None));
+                (None, vec![])
+            }
+        };
+
+        // NB: the shard's guard has been dropped by now, so woken tasks do not block on it.
+        let count = woken.len();
+        for waker in woken {
+            waker.wake();
+        }
+        (old, count)
+    }
+
+    /// Inserts a key-value pair into the map, returning the old value if the key was filled
+    /// along with a mutable reference to the new value.
+    ///
+    /// This is `insert` and `get_mut` under a single shard lock, so no other task can change the
+    /// key in between. Waiting tasks are woken while the returned guard is still held.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let (old, new) = map.insert_full("Lev Chernyi".to_string(), 1878);
+    /// assert_eq!((old, *new.value()), (None, 1878));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_full(&self, key: K, value: V) -> (Option<V>, RefMut<'_, K, V, S>) {
+        self.hooks.inserted(&key, &value);
+        let entry = self.map.entry(key);
+        self.listeners.filled(entry.key(), &value);
+        let (old, inner) = match entry {
+            Occupied(mut entry)  => {
+                let old = match mem::replace(entry.get_mut(), Filled(value, None)) {
+                    Waiting(wakers)  => {
+                        self.count_fills(1);
+                        wakers.wake();
+                        None
+                    }
+                    Filled(value, _) => Some(value),
+                };
+                (old, entry.into_ref())
+            }
+            Vacant(slot)     => {
+                self.count_fills(1);
+                (None, slot.insert(Filled(value, None)))
+            }
+        };
+        (old, self.make_ref_mut(inner))
+    }
+
+    /// Fills a key if it is missing or waiting to be filled, returning a mutable reference to
+    /// the value.
+    ///
+    /// If the key is already filled, the map is left unchanged and this returns a reference to
+    /// the existing value along with the value which was not inserted. Checking the key and
+    /// filling it happen under one shard lock, so of several racing calls exactly one fills it.
+    /// Waiting tasks are woken while the returned guard is still held.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    ///
+    /// assert!(map.fill_if_absent("Ukraine".to_string(), "Makhno").is_ok());
+    /// match map.fill_if_absent("Ukraine".to_string(), "Hryhoriv") {
+    ///     Ok(_)               => unreachable!(),
+    ///     Err((winner, ours)) => {
+    ///         assert_eq!(winner.value(), &"Makhno");
+    ///         assert_eq!(ours, "Hryhoriv");
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn fill_if_absent(&self, key: K, value: V)
+        -> Result<RefMut<'_, K, V, S>, (RefMut<'_, K, V, S>, V)>
+    {
+        match self.entry(key) {
+            Entry::Occupied(entry)  => Err((entry.into_ref(), value)),
+            Entry::Vacant(entry)    => Ok(entry.insert(value)),
+        }
+    }
+
+    /// Fills a key if it is missing or waiting to be filled, returning a mutable reference to
+    /// whichever value ends up in the map.
+    ///
+    /// This is `fill_if_absent` for callers which do not need to know which value won: if the
+    /// key is already filled, `value` is dropped and the existing value is left in place.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    ///
+    /// assert_eq!(*map.insert_if_absent("Paris".to_string(), "Varlin").value(), "Varlin");
+    /// assert_eq!(*map.insert_if_absent("Paris".to_string(), "Blanqui").value(), "Varlin");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_if_absent(&self, key: K, value: V) -> RefMut<'_, K, V, S> {
+        self.entry(key).or_insert(value)
+    }
+
+    /// Inserts a key-value pair into the map, computing the value under the key's shard lock.
+    ///
+    /// This behaves like `insert`, except that `f` is called only once the key's entry has been
+    /// locked, and the `on_insert` hook runs while the lock is still held. Because the lock is
+    /// held while `f` runs, `f` must not access the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// assert!(map.insert_with("Johann Most".to_string(), || 0).is_none());
+    /// assert_eq!(map.insert_with("Johann Most".to_string(), || 1), Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_with(&self, key: K, f: impl FnOnce() -> V) -> Option<V> {
+        match self.map.entry(key) {
+            Occupied(mut entry)  => {
+                let value = f();
+                self.hooks.inserted(entry.key(), &value);
+                self.listeners.filled(entry.key(), &value);
+                match mem::replace(entry.get_mut(), Filled(value, None)) {
+                    Waiting(wakers)  => {
+                        self.count_fills(1);
+                        drop(entry); // drop early to release lock before waking other tasks
+                        wakers.wake();
+                        None
+                    }
+                    Filled(value, _) => Some(value),
+                }
+            }
+            Vacant(slot)     => {
+                let value = f();
+                self.hooks.inserted(slot.key(), &value);
+                self.listeners.filled(slot.key(), &value);
+                self.count_fills(1);
+                slot.insert(Filled(value, None));
+                None
+            }
+        }
+    }
+
+    /// Fills a key with a value computed under its shard lock, only if the key is waiting to be
+    /// filled.
+    ///
+    /// If the key is missing or already filled, `f` is never called and the map is left
+    /// unchanged. Returns `true` if the key was filled, in which case its waiters are woken up
+    /// after the lock is released. As with `insert_with`, `f` must not access the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// assert!(!map.insert_with_if_waiting("Louise Michel".to_string(), || unreachable!()));
+    ///
+    /// let wait_fut = map.wait("Louise Michel");
+    /// assert!(map.insert_with_if_waiting("Louise Michel".to_string(), || 1871));
+    /// assert_eq!(wait_fut.await.unwrap().value(), &1871);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_with_if_waiting(&self, key: K, f: impl FnOnce() -> V) -> bool {
+        match self.map.entry(key) {
+            Occupied(mut entry) if matches!(entry.get(), Waiting(_)) => {
+                let value = f();
+                self.hooks.inserted(entry.key(), &value);
+                self.listeners.filled(entry.key(), &value);
+                if let Waiting(wakers) = mem::replace(entry.get_mut(), Filled(value, None)) {
+                    self.count_fills(1);
+                    drop(entry); // drop early to release lock before waking other tasks
+                    wakers.wake();
+                }
+                true
+            }
+            _                                                       => false,
+        }
+    }
+
+    /// Inserts many key-value pairs into the map, grouped by shard.
+    ///
+    /// Rather than locking a shard once per key, the pairs are sorted by the shard they belong
+    /// to and each shard is locked once for all of its pairs. Any pending `wait` calls for the
+    /// inserted keys are woken up together once every pair has been inserted, rather than one
+    /// key at a time.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<i32, i32> = WaitMap::new();
+    /// map.insert_sorted((0..100).map(|i| (i, i * 2)));
+    ///
+    /// assert_eq!(map.get(&21).unwrap().value(), &42);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_sorted<I: IntoIterator<Item = (K, V)>>(&self, iter: I) {
+        let iter = iter.into_iter().inspect(|(key, value)| self.hooks.inserted(key, value));
+        let batches = self.group_by_shard(iter, |(key, _)| key);
+
+        let mut woken = vec![];
+        for (shard, batch) in self.map.shards().iter().zip(batches) {
+            if batch.is_empty() { continue; }
+
+            let mut shard = shard.write();
+
+            for (key, value) in batch {
+                self.listeners.filled(&key, &value);
+                let old = shard.insert(key, SharedValue::new(Filled(value, None)));
+                match old.map(SharedValue::into_inner) {
+                    Some(Filled(..))        => {}
+                    Some(Waiting(wakers))   => {
+                        self.count_fills(1);
+                        woken.push(wakers);
+                    }
+                    None                    => self.count_fills(1),
+                }
+            }
+        }
+
+        woken.into_iter().for_each(WakerSet::wake);
+    }
+
+    /// Inserts many key-value pairs into the map, returning each key with the value it replaced.
+    ///
+    /// The pairs are inserted one at a time, exactly as `insert` would, except that pending
+    /// `wait` calls are woken together once every pair has been inserted, rather than as their
+    /// keys are filled. The returned vector is in the same order as the input, holding `None`
+    /// for keys which were not filled before.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Voltairine de Cleyre".to_string(), 1);
+    ///
+    /// let old = map.insert_many_returning_old(vec![
+    ///     ("Voltairine de Cleyre".to_string(), 2),
+    ///     ("Dyer Lum".to_string(), 3),
+    /// ]);
+    /// assert_eq!(old, vec![
+    ///     ("Voltairine de Cleyre".to_string(), Some(1)),
+    ///     ("Dyer Lum".to_string(), None),
+    /// ]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn insert_many_returning_old<I: IntoIterator<Item = (K, V)>>(&self, iter: I)
+        -> Vec<(K, Option<V>)>
+        where K: Clone
+    {
+        let mut woken = vec![];
+        let old = iter.into_iter().map(|(key, value)| {
+            let (old, wakers) = self.insert_deferred(key.clone(), value);
+            woken.extend(wakers);
+            (key, old)
+        }).collect();
+
+        woken.into_iter().for_each(WakerSet::wake);
+        old
+    }
+
+    /// Reserves many keys to be waited on, grouped by shard.
+    ///
+    /// This puts a placeholder into the map for each key which is not already present, just as
+    /// `reserve_wait` does, but locks each shard only once. Keys which are already waiting or
+    /// filled are left alone.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.reserve_all(vec!["Proudhon".to_string(), "Stirner".to_string()]);
+    ///
+    /// assert!(map.contains_waiting("Proudhon"));
+    /// assert!(map.contains_waiting("Stirner"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reserve_all<I: IntoIterator<Item = K>>(&self, keys: I) {
+        if self.is_closed() { return; }
+        let batches = self.group_by_shard(keys, |key| key);
+
+        for (shard, batch) in self.map.shards().iter().zip(batches) {
+            if batch.is_empty() { continue; }
+
+            let mut shard = shard.write();
+            for key in batch {
+                shard.entry(key).or_insert_with(|| SharedValue::new(Waiting(WakerSet::new())));
+            }
+        }
+    }
+
+    // Sorts items into one batch per shard, according to the shard their key belongs to.
+    fn group_by_shard<T>(&self, items: impl IntoIterator<Item = T>, key: impl Fn(&T) -> &K)
+        -> Vec<Vec<T>>
+    {
+        let mut batches: Vec<Vec<T>> = self.map.shards().iter().map(|_| Vec::new()).collect();
+        for item in items {
+            let idx = self.shard_index(key(&item));
+            batches[idx].push(item);
+        }
+        batches
+    }
+
+    /// Mutates every filled value in the map in place.
+    ///
+    /// Entries which are waiting to be filled are skipped. Each shard is write locked once
+    /// while `f` is applied to all of its values.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Errico Malatesta".to_string(), 1);
+    /// map.insert("Carlo Cafiero".to_string(), 2);
+    /// let _wait_fut = map.wait("Andrea Costa");
+    ///
+    /// map.update_all(|_, value| *value *= 10);
+    ///
+    /// assert_eq!(map.get("Errico Malatesta").unwrap().value(), &10);
+    /// assert_eq!(map.get("Carlo Cafiero").unwrap().value(), &20);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update_all(&self, mut f: impl FnMut(&K, &mut V)) {
+        for shard in self.map.shards() {
+            for (key, entry) in shard.write().iter_mut() {
+                if let Filled(value, _) = entry.get_mut() {
+                    f(key, value);
+                }
+            }
+        }
+    }
+
+    /// Makes a new `WaitMap` with the same keys and hasher, with each filled value transformed
+    /// by `f`.
+    ///
+    /// Entries which are waiting to be filled are not carried over, so the new map has nothing
+    /// waiting on it.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Mikhail Bakunin".to_string(), 1814);
+    /// let _wait_fut = map.wait("Sergey Nechayev");
+    ///
+    /// let years: WaitMap<String, String> = map.map_values(|year| year.to_string());
+    /// assert_eq!(years.get("Mikhail Bakunin").unwrap().value(), "1814");
+    /// assert!(years.get("Sergey Nechayev").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn map_values<U>(&self, mut f: impl FnMut(&V) -> U) -> WaitMap<K, U, S>
+        where K: Clone
+    {
+        let map = WaitMap::from_dashmap(DashMap::with_hasher(self.map.hasher().clone()));
+        for entry in self.map.iter() {
+            if let Filled(value, _) = entry.value() {
+                map.map.insert(entry.key().clone(), Filled(f(value), None));
+                map.count_fills(1);
+            }
+        }
+        map
+    }
+
+    /// Returns a reference to a key's value, or `None` if the key is missing or waiting to be
+    /// filled.
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<Ref<'_, K, V, S>>
+        where K: Borrow<Q>
+    {
+        let inner = self.map.get(key)?;
+        match inner.value() {
+            Filled(..)  => Some(self.make_ref(inner)),
+            Waiting(_)  => None,
+        }
+    }
+
+    /// Returns a mutable reference to a key's value, or `None` if the key is missing or
+    /// waiting to be filled.
+    pub fn get_mut<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
+        where K: Borrow<Q>
+    {
+        let inner = self.map.get_mut(key)?;
+        match inner.value() {
+            Filled(..)  => Some(self.make_ref_mut(inner)),
+            Waiting(_)  => None,
+        }
+    }
+
+    /// Returns a clone of a key and its value, without holding a guard into the map.
+    ///
+    /// Returns `None` if the key is missing or waiting to be filled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Lucy Parsons".to_string(), 1886);
+    ///
+    /// assert_eq!(map.get_pair_cloned("Lucy Parsons"), Some(("Lucy Parsons".to_string(), 1886)));
+    /// assert_eq!(map.get_pair_cloned("Albert Parsons"), None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_pair_cloned<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q> + Clone,
+        V: Clone,
+    {
+        let entry = self.map.get(key)?;
+        match entry.pair() {
+            (key, Filled(value, _)) => Some((key.clone(), value.clone())),
+            (_, Waiting(_))         => None,
+        }
+    }
+
+    /// Returns a clone of a key's value, if it is filled, along with a stream of the values it
+    /// is filled with from then on.
+    ///
+    /// Reading the value and subscribing to the key happen under the key's shard lock, so no
+    /// value is missed in between: the first value the `Watcher` yields is the first one to
+    /// replace the returned value. Unlike `wait`, this does not put a placeholder into the map
+    /// for a missing key.
+    ///
+    /// While the `Watcher` is alive, every insert into the map, whichever key it fills, takes a
+    /// map-wide lock and checks the watched key while its own shard is still locked, so inserts
+    /// into different shards no longer run in parallel. See [`Watcher`] for why.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    /// map.insert("Ricardo Flores Magón".to_string(), "Regeneración");
+    ///
+    /// let (current, mut watcher) = map.get_or_subscribe("Ricardo Flores Magón");
+    /// assert_eq!(current, Some("Regeneración"));
+    ///
+    /// map.insert("Ricardo Flores Magón".to_string(), "Tierra y Libertad");
+    /// assert_eq!(watcher.next().await, Some("Tierra y Libertad"));
+    ///
+    /// map.cancel_all();
+    /// assert_eq!(watcher.next().await, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_or_subscribe<'b, Q: ?Sized + Hash + Eq>(&self, qey: &'b Q)
+        -> (Option<V>, Watcher<'_, K, V, S>)
+    where
+        K: Borrow<Q> + From<&'b Q> + Send + 'static,
+        V: Clone + Send + 'static,
+    {
+        // NB: the shard is locked even if the key is missing, so that it cannot be filled
+        // between reading it and subscribing to it.
+        let shard = self.map.shards()[self.shard_index(qey)].read();
+        let current = match shard.get(qey).map(SharedValue::get) {
+            Some(Filled(value, _)) => Some(value.clone()),
+            _                      => None,
+        };
+        let watcher = Watcher::new(self, K::from(qey));
+        drop(shard);
+        (current, watcher)
+    }
+
+    /// Returns clones of every filled key-value pair in the map, in no particular order.
+    ///
+    /// Unlike iterating over the map, no locks are held once this returns. The shards are
+    /// copied one at a time, each under its read lock, so the copy is consistent within each
+    /// shard but entries in different shards may be copied at different moments. Entries which
+    /// are waiting to be filled are skipped.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Nestor Makhno".to_string(), 1888);
+    /// let _wait_fut = map.wait("Peter Arshinov");
+    ///
+    /// assert_eq!(map.snapshot(), vec![("Nestor Makhno".to_string(), 1888)]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut pairs = Vec::new();
+        for shard in self.map.shards() {
+            for (key, entry) in shard.read().iter() {
+                if let Filled(value, _) = entry.get() {
+                    pairs.push((key.clone(), value.clone()));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Returns clones of every filled key-value pair in the map, as of a single moment.
+    ///
+    /// Unlike `snapshot`, every shard is read locked before any entry is copied, and the locks
+    /// are only released once the copy is complete, so no insert or removal can land halfway
+    /// through it. The price is that no task can write to any part of the map while the copy is
+    /// being made. The shards are always locked in order of their index, so two calls racing
+    /// each other, or racing `par_iter`, cannot deadlock; but this must not be called while the
+    /// current task holds a guard into the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Fernand Pelloutier".to_string(), 1867);
+    ///
+    /// assert_eq!(map.locked_snapshot(), vec![("Fernand Pelloutier".to_string(), 1867)]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn locked_snapshot(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let shards: Vec<_> = self.map.shards().iter().map(|shard| shard.read()).collect();
+        shards.iter().flat_map(|shard| shard.iter()).filter_map(|(key, entry)| match entry.get() {
+            Filled(value, _) => Some((key.clone(), value.clone())),
+            Waiting(_)       => None,
+        }).collect()
+    }
+
+    /// Gets a mutable reference to a key's value, filling it with the default value first if
+    /// it is not filled.
+    ///
+    /// This is shorthand for `entry(key).or_default()`. If the key was waiting to be filled,
+    /// any pending `wait` calls for it are woken up.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, u32> = WaitMap::new();
+    ///
+    /// *map.get_or_default("Sébastien Faure") += 1;
+    /// *map.get_or_default("Sébastien Faure") += 1;
+    /// assert_eq!(map.get("Sébastien Faure").unwrap().value(), &2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_or_default<'b, Q: ?Sized>(&self, key: &'b Q) -> RefMut<'_, K, V, S>
+    where
+        K: From<&'b Q>,
+        V: Default,
+    {
+        self.fill_ref(K::from(key), V::default)
+    }
+
+    /// Gets the entry for a key, for in-place manipulation.
+    ///
+    /// The entry holds a write lock on the key's shard until it is dropped. Keys which are
+    /// waiting to be filled are vacant entries; filling them wakes up any pending `wait` calls.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::{Entry, WaitMap};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// *map.entry("Johann Most".to_string()).or_insert(0).value_mut() += 1;
+    /// *map.entry("Johann Most".to_string()).or_insert(0).value_mut() += 1;
+    /// assert_eq!(map.get("Johann Most").unwrap().value(), &2);
+    ///
+    /// let removed = map.entry("Johann Most".to_string()).and_remove_if(|_, count| *count > 1);
+    /// assert_eq!(removed, Some(2));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S> {
+        Entry::new(self, key)
+    }
+
+    /// Gets the entry for a key, or returns `None` if its shard is currently locked.
+    ///
+    /// This lets latency-sensitive code fall back to waiting or retrying instead of blocking
+    /// the thread on a contended shard; it never blocks. The returned `Entry` holds on to the
+    /// lock taken here, and otherwise behaves the same as one returned by `entry`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let entry = map.try_entry("Johann Most".to_string()).unwrap();
+    /// entry.or_insert(0);
+    ///
+    /// let guard = map.get_mut("Johann Most").unwrap();
+    /// assert!(map.try_entry("Johann Most".to_string()).is_none());
+    /// drop(guard);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_entry(&self, key: K) -> Option<Entry<'_, K, V, S>> {
+        let shard = self.map.shards()[self.shard_index(&key)].try_write()?;
+        Some(Entry::locked(self, LockedKey::new(shard, key)))
+    }
+
+    /// Iterates over the keys which are waiting to be filled, with the number of tasks waiting
+    /// on each of them.
+    ///
+    /// Filled entries are skipped. Like iterating over a `DashMap`, this holds a read lock on
+    /// each shard while its entries are being yielded.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Peter Kropotkin".to_string(), 0);
+    /// let _wait_fut = map.wait("Mikhail Bakunin");
+    ///
+    /// let waiting: Vec<String> = map.iter_waiting().map(|w| w.key().clone()).collect();
+    /// assert_eq!(waiting, vec!["Mikhail Bakunin".to_string()]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter_waiting(&self) -> impl Iterator<Item = WaitingRef<'_, K, V, S>> {
+        self.map.iter()
+            .filter(|entry| matches!(entry.value(), Waiting(_)))
+            .map(|inner| WaitingRef { inner })
+    }
+
+    /// Returns `true` if the key is in the map and waiting to be filled.
+    pub fn contains_waiting<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
+        where K: Borrow<Q>
+    {
+        self.map.get(key).is_some_and(|entry| matches!(entry.value(), Waiting(_)))
+    }
+
+    /// Returns the index of the shard a key belongs to.
+    ///
+    /// The key is hashed with the map's hasher and mapped to a shard the same way the map does
+    /// internally, so the index is always less than the number of shards. Keys with the same
+    /// index are guarded by the same lock, which lets callers group keys by shard or order
+    /// their own locking before calling bulk operations such as `insert_sorted`.
+    /// ```
+    /// # extern crate waitmap;
+    /// # use waitmap::WaitMap;
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// let shard = map.shard_index("Errico Malatesta");
+    /// assert_eq!(shard, map.shard_index(&"Errico Malatesta".to_string()));
+    /// ```
+    pub fn shard_index<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> usize
+        where K: Borrow<Q>
+    {
+        self.map.determine_map(key)
+    }
+
+    /// Locks one shard for writing and runs `f` with a view of it, returning what `f` returns.
+    ///
+    /// This lets many operations on keys known to share a shard, as told by `shard_index`, be
+    /// done under a single lock acquisition. The view panics if it is used with a key from any
+    /// other shard, and this panics if `shard` is not a valid shard index. Tasks waiting on
+    /// keys filled or removed through the view are woken once the lock has been released, but
+    /// the map's hooks run while it is held.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Ricardo Mella".to_string(), 1);
+    ///
+    /// let shard = map.shard_index("Ricardo Mella");
+    /// let old = map.with_shard(shard, |mut view| {
+    ///     let old = view.remove("Ricardo Mella").unwrap();
+    ///     view.insert("Ricardo Mella".to_string(), old + 1);
+    ///     old
+    /// });
+    /// assert_eq!(old, 1);
+    /// assert_eq!(map.get("Ricardo Mella").unwrap().value(), &2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_shard<R>(&self, shard: usize, f: impl FnOnce(ShardView<'_, K, V, S>) -> R) -> R {
+        let mut woken = vec![];
+        let mut guard = self.map.shards()[shard].write();
+        let result = f(ShardView::new(self, shard, &mut guard, &mut woken));
+
+        drop(guard); // drop early to release lock before waking other tasks
+        woken.into_iter().for_each(WakerSet::wake);
+        result
+    }
+
+    /// Returns the number of filled keys in the map.
+    ///
+    /// Keys which are waiting to be filled are not counted. The count is kept up to date as
+    /// keys are filled and removed, so this does not lock any shards and is cheap enough to
+    /// call on a hot path. Under concurrent modification it is only a snapshot, and it may
+    /// briefly disagree with what a scan of the map would find.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// let _wait_fut = map.wait("Nestor Makhno");
+    /// assert!(map.is_empty());
+    ///
+    /// map.insert("Nestor Makhno".to_string(), 1888);
+    /// map.insert("Maria Nikiforova".to_string(), 1885);
+    /// assert_eq!(map.len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn len(&self) -> usize {
+        self.filled.load(Ordering::Relaxed)
+    }
+
+    /// Returns `true` if no keys in the map are filled.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reserves capacity for at least `additional` more entries, so that a burst of inserts
+    /// does not have to grow the map as it goes.
+    ///
+    /// The capacity is shared by filled keys and keys which are waiting to be filled, so
+    /// `reserve_wait` and `reserve_all` use it up just as inserting does. It is split evenly
+    /// between the map's shards, so keys which all land in the same shard may still make it
+    /// grow.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.reserve(1024);
+    /// assert!(map.capacity() >= 1024);
+    ///
+    /// map.insert("Federica Montseny".to_string(), 1905);
+    /// map.shrink_to_fit();
+    /// assert!(map.capacity() < 1024);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reserve(&self, additional: usize) {
+        let shards = self.map.shards();
+        let per_shard = additional.div_ceil(shards.len());
+        for shard in shards {
+            shard.write().reserve(per_shard);
+        }
+    }
+
+    /// Returns how many entries, filled or waiting, the map can hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.map.capacity()
+    }
+
+    /// Shrinks the map's capacity as much as possible for the entries it holds, filled or
+    /// waiting.
+    pub fn shrink_to_fit(&self) {
+        self.map.shrink_to_fit()
+    }
+
+    /// Removes every placeholder which no task is waiting on, returning how many were removed.
+    ///
+    /// Dropping the last wait which has polled a key already removes its placeholder, so this is
+    /// only needed for placeholders left behind in other ways: by waits dropped before they
+    /// were polled, by `reserve_all` or `wait_first`, or by cancelled waits. A wait which has
+    /// been created but not polled yet still counts as waiting on its key, so its placeholder is
+    /// kept.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.reserve_all(vec!["Louise Michel".to_string(), "Paule Minck".to_string()]);
+    /// map.insert("Louise Michel".to_string(), 1830);
+    ///
+    /// assert_eq!(map.compact_all(), 1);
+    /// assert!(map.get("Paule Minck").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn compact_all(&self) -> usize where K: Clone {
+        let unwaited: Vec<K> = self.map.iter().filter_map(|entry| match entry.value() {
+            Waiting(wakers) if wakers.is_unwaited() => Some(entry.key().clone()),
+            _                                       => None,
+        }).collect();
+        // NB: a task may start waiting on a key between the scan and its removal, so the check
+        // is repeated under the lock used to remove the placeholder, as in `drop_waker`.
+        unwaited.iter().filter(|key| {
+            self.map.remove_if(key, |_, entry| match entry {
+                Waiting(wakers) => wakers.is_unwaited(),
+                Filled(..)      => false,
+            }).is_some()
+        }).count()
+    }
+
+    /// Estimates how many bytes of memory the map is using.
+    ///
+    /// This is only an approximation: it counts the slots the map has allocated for entries
+    /// (filled or waiting) and the wakers of waiting tasks, but not any heap memory owned by
+    /// the keys and values themselves, nor allocator overhead.
+    pub fn approx_memory_bytes(&self) -> usize {
+        let mut capacity = 0;
+        let mut waiters = 0;
+        for shard in self.map.shards() {
+            let shard = shard.read();
+            capacity += shard.capacity();
+            waiters += shard.values().map(|entry| match entry.get() {
+                Waiting(wakers) => wakers.len(),
+                Filled(..)      => 0,
+            }).sum::<usize>();
+        }
+        mem::size_of::<Self>()
+            + capacity * mem::size_of::<(K, SharedValue<WaitEntry<V>>)>()
+            + waiters * mem::size_of::<Option<Waker>>()
+    }
+
+    /// Returns the largest number of waiters any key waiting to be filled has room for.
+    ///
+    /// The slots of waiters which stop waiting are not reused by later waiters, so a key which
+    /// stays waiting for a long time keeps growing as tasks come and go. This is a debugging
+    /// aid: if it keeps climbing for a workload whose concurrency is bounded, keys are living
+    /// long enough for that growth to matter.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.reserve_wait_capacity("Rudolf Rocker", 16);
+    /// assert!(map.max_waker_set_capacity() >= 16);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn max_waker_set_capacity(&self) -> usize {
+        let mut max = 0;
+        for shard in self.map.shards() {
+            for entry in shard.read().values() {
+                if let Waiting(wakers) = entry.get() {
+                    max = max.max(wakers.capacity());
+                }
+            }
+        }
+        max
+    }
+
+    /// Waits for a key to be filled, resolving to `None` if it is removed or cancelled first.
+    ///
+    /// The wait counts as waiting on the key from the moment it is created, not only once it is
+    /// first polled, so other waits on the key being dropped does not remove its placeholder.
+    pub fn wait<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> impl FusedFuture<Output = Option<Ref<'a, K, V, S>>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        Wait::new(self, qey, reservation)
+    }
+
+    /// Waits for a key to be filled, resolving to a clone of the key and its value.
+    ///
+    /// The guard is dropped as soon as the pair has been cloned, so the result has no lifetime
+    /// tied to the map and can be sent over a channel or held across other awaits.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let insert_fut = async { map.insert("Francesc Ferrer".to_string(), 1909) };
+    /// let wait_fut = map.wait_entry("Francesc Ferrer");
+    ///
+    /// let (_, pair) = insert_fut.join(wait_fut).await;
+    /// assert_eq!(pair, Some(("Francesc Ferrer".to_string(), 1909)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_entry<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> impl Future<Output = Option<(K, V)>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q> + Clone,
+        V: Clone,
+    {
+        let wait = self.wait(qey);
+        async move {
+            wait.await.map(|r| (r.key().clone(), r.value().clone()))
+        }
+    }
+
+    /// Waits for a key to be filled, resolving to a clone of its value, or to `default()` if
+    /// the wait is cancelled.
+    ///
+    /// The key counts as filled if it held a value when the wait was last polled, even if it
+    /// was removed or cancelled right after, so a value which existed is preferred over the
+    /// default.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let wait_fut = map.wait_or("Severino Di Giovanni", || -1);
+    /// let cancel_fut = async { map.cancel("Severino Di Giovanni") };
+    /// let (value, _) = wait_fut.join(cancel_fut).await;
+    /// assert_eq!(value, -1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_or<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(
+        &'a self,
+        qey: &'b Q,
+        default: impl FnOnce() -> V + 'f,
+    ) -> impl Future<Output = V> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+        V: Clone,
+    {
+        let wait = self.wait(qey);
+        async move {
+            match wait.await {
+                Some(entry) => entry.value().clone(),
+                None        => default(),
+            }
+        }
+    }
+
+    /// Waits for a key to be filled, resolving to the result of calling `f` on its value, or
+    /// to `None` if the wait is cancelled.
+    ///
+    /// `f` is called while the shard's read lock is held, and the lock is released as soon as
+    /// it returns, so no guard outlives the future. Like holding a `Ref`, this means `f` must
+    /// not write to the map, or it may deadlock.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<&str>> = WaitMap::new();
+    ///
+    /// let wait_fut = map.wait_then("Ricardo Mella", |works| works.len());
+    /// let insert_fut = async { map.insert("Ricardo Mella".to_string(), vec!["La Anarquía"]) };
+    /// let (count, _) = wait_fut.join(insert_fut).await;
+    /// assert_eq!(count, Some(1));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_then<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq, R>(
+        &'a self,
+        qey: &'b Q,
+        f: impl FnOnce(&V) -> R + 'f,
+    ) -> impl Future<Output = Option<R>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let wait = self.wait(qey);
+        async move {
+            wait.await.map(|entry| f(entry.value()))
+        }
+    }
+
+    /// Waits for a key to be filled, resolving to a clone-on-write reference to its value.
+    ///
+    /// The reference borrows the value, holding the shard's read lock like the `Ref` that
+    /// `wait` resolves to, until `to_mut` is called on it: then the value is cloned and the
+    /// lock released, so the copy can be changed without a second lookup and without touching
+    /// the map. It evaluates to `None` if the wait is cancelled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<&str>> = WaitMap::new();
+    /// map.insert("Emma Goldman".to_string(), vec!["Anarchism"]);
+    ///
+    /// let mut books = map.wait_cow("Emma Goldman").await.unwrap();
+    /// assert_eq!(books.len(), 1);
+    /// books.to_mut().push("Living My Life");
+    ///
+    /// assert_eq!(*books, ["Anarchism", "Living My Life"]);
+    /// assert_eq!(*map.get("Emma Goldman").unwrap(), ["Anarchism"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_cow<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> impl Future<Output = Option<CowRef<'a, K, V, S>>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+        V: Clone,
+    {
+        let wait = self.wait(qey);
+        async move { wait.await.map(CowRef::Borrowed) }
+    }
+
+    /// Reserves a key to be waited on, returning a future which waits for it to be filled.
+    ///
+    /// Like `wait`, this puts a placeholder for the key into the map before returning, so the
+    /// key is waiting to be filled even if the future is never polled (and, for example,
+    /// `iter_waiting` will list it). The returned boolean is `true` if this call created the
+    /// placeholder, and `false` if the key was already waiting or already filled.
+    ///
+    /// Until it is first polled, the future holds a reservation on the placeholder, so other
+    /// tasks dropping their waits on the key do not remove it, and neither does `compact_all`.
+    /// Dropping the future only removes the placeholder if the future has been polled and no
+    /// other task is waiting on the key. A future dropped before it was polled leaves the
+    /// placeholder behind, for `compact_all` to remove.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let (wait_fut, created) = map.reserve_wait("Gustav Landauer");
+    /// assert!(created);
+    /// let (_, created) = map.reserve_wait("Gustav Landauer");
+    /// assert!(!created);
+    ///
+    /// map.insert("Gustav Landauer".to_string(), 0);
+    /// assert_eq!(wait_fut.await.unwrap().value(), &0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reserve_wait<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> (Wait<'a, 'b, K, V, S, Q>, bool)
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (created, reservation) = self.reserve_key(K::from(qey));
+        (Wait::new(self, qey, reservation), created)
+    }
+
+    /// Waits for a key to be filled, returning a handle which can cancel this one wait.
+    ///
+    /// Cancelling through the handle makes the returned future evaluate to `None`, without
+    /// disturbing any other tasks waiting on the same key. In contrast, `cancel` cancels every
+    /// wait on a key and removes it from the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let (wait_fut, handle) = map.wait_with_handle("Lucy Parsons");
+    /// let other_fut = map.wait("Lucy Parsons");
+    ///
+    /// handle.cancel();
+    /// assert!(wait_fut.await.is_none());
+    ///
+    /// map.insert("Lucy Parsons".to_string(), 0);
+    /// assert_eq!(other_fut.await.unwrap().value(), &0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_with_handle<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> (Wait<'a, 'b, K, V, S, Q>, WaitHandle)
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        Wait::with_handle(self, qey, reservation)
+    }
+
+    /// Waits for a key to be filled, like `wait`, unless a `CancelToken` is cancelled first.
+    ///
+    /// Cancelling the token makes the returned future evaluate to `None` and stop waiting on
+    /// the key, just as cancelling a `WaitHandle` would, but a single token can cancel any
+    /// number of waits. This ties waits into a wider cancellation scope without having to
+    /// cancel each of their keys, which would also cancel every other task waiting on them.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::{CancelToken, WaitMap};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// let token = CancelToken::new();
+    ///
+    /// let first_fut = map.wait_with_token("Élisée Reclus", &token);
+    /// let second_fut = map.wait_with_token("Jean Grave", &token);
+    /// let other_fut = map.wait("Jean Grave");
+    ///
+    /// token.cancel();
+    /// assert!(first_fut.await.is_none());
+    /// assert!(second_fut.await.is_none());
+    ///
+    /// map.insert("Jean Grave".to_string(), 1854);
+    /// assert_eq!(other_fut.await.unwrap().value(), &1854);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_with_token<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q, token: &CancelToken)
+        -> Wait<'a, 'b, K, V, S, Q>
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        Wait::with_token(self, qey, reservation, token)
+    }
+
+    /// Waits for the first of several keys to be filled, resolving to its index in `keys` and
+    /// a reference to it.
+    ///
+    /// Like `wait`, this puts a placeholder into the map for each key which is missing. If
+    /// several of the keys are filled when the future is polled, the one which comes first in
+    /// `keys` wins, so the keys can be given in order of preference. The future evaluates to
+    /// `None` once all of the keys have been cancelled. When it completes, it stops waiting on
+    /// the other keys, but their placeholders stay in the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    ///
+    /// let wait_fut = map.wait_first(&["Mikhail Bakunin", "Peter Kropotkin"]);
+    /// let insert_fut = async { map.insert("Peter Kropotkin".to_string(), "Fields, Factories") };
+    ///
+    /// let (first, _) = wait_fut.join(insert_fut).await;
+    /// let (idx, entry) = first.unwrap();
+    /// assert_eq!(idx, 1);
+    /// assert_eq!(entry.value(), &"Fields, Factories");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_first<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, keys: &'b [&'b Q])
+        -> WaitFirst<'a, 'b, K, V, S, Q>
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let reservations = keys.iter().map(|&key| self.reserve_key(K::from(key)).1)
+            .collect();
+        WaitFirst::new(self, keys, reservations)
+    }
+
+    /// Waits for any key matching a predicate to be filled, resolving to a clone of its key and
+    /// value.
+    ///
+    /// If a matching key is already filled when the future is first polled, it resolves to that
+    /// key right away. Otherwise it resolves the next time a matching key is filled or
+    /// replaced, and to `None` if the map's waits are cancelled with `cancel_all`.
+    ///
+    /// Unlike other waits, this is not tied to a single key, so no placeholder is put into the
+    /// map. Instead, while any such wait is pending, every insert into the map calls `pred`
+    /// under a map-wide lock, which makes inserts slower for as long as it is waiting. When no
+    /// wait of this kind is pending, inserts only pay for checking an atomic counter. `pred` is
+    /// called while a shard of the map is locked, so it must not access the map.
+    ///
+    /// The map-wide lock is taken while the inserted key's shard is still locked, so while
+    /// this is pending, inserts into different shards are serialized. This is what lets a key
+    /// filled while the map is being looked through be either found or passed to `pred`;
+    /// calling `pred` after the shard is unlocked would let such a key slip through.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("tenant-b/Nestor Makhno".to_string(), 0);
+    ///
+    /// let wait_fut = map.wait_matching(|key| key.starts_with("tenant-a/"));
+    /// let insert_fut = async { map.insert("tenant-a/Maria Nikiforova".to_string(), 1) };
+    /// let (found, _) = wait_fut.join(insert_fut).await;
+    /// assert_eq!(found, Some(("tenant-a/Maria Nikiforova".to_string(), 1)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_matching(&self, pred: impl Fn(&K) -> bool + Send + Sync + 'static)
+        -> WaitMatching<'_, K, V, S>
+    where
+        K: Clone + Send + 'static,
+        V: Clone + Send + 'static,
+    {
+        WaitMatching::new(self, Arc::new(pred), true)
+    }
+
+    /// Waits for the next time any key is filled or replaced, resolving to a clone of its key
+    /// and value.
+    ///
+    /// Keys which were filled before the future is first polled do not count. This has the same
+    /// cost as `wait_matching`: while it is pending, every insert into the map takes a map-wide
+    /// lock and clones the inserted pair while the key's shard is still locked, so inserts into
+    /// different shards are serialized behind one another instead of running in parallel. It
+    /// resolves to `None` if the map's waits are cancelled with `cancel_all`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Alexander Berkman".to_string(), 0);
+    ///
+    /// let wait_fut = map.wait_any_insert();
+    /// let insert_fut = async { map.insert("Emma Goldman".to_string(), 1) };
+    /// let (inserted, _) = wait_fut.join(insert_fut).await;
+    /// assert_eq!(inserted, Some(("Emma Goldman".to_string(), 1)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_any_insert(&self) -> WaitMatching<'_, K, V, S>
+    where
+        K: Clone + Send + 'static,
+        V: Clone + Send + 'static,
+    {
+        WaitMatching::new(self, Arc::new(|_: &K| true), false)
+    }
+
+    /// Waits for a key to be removed from the map, resolving to `true` once it is, or to
+    /// `false` if the wait is cancelled by `cancel_all` or a shutdown.
+    ///
+    /// This is the inverse of `wait`, for keys whose presence stands for something being held:
+    /// a key counts as removed as soon as it holds no value, so the future resolves straight
+    /// away if the key is missing or only waiting to be filled when it is first polled. If the
+    /// key is removed and filled again before the future is polled, it still resolves to
+    /// `true`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    /// map.insert("Gustav Landauer".to_string(), "Der Sozialist");
+    ///
+    /// let removed_fut = map.wait_removed("Gustav Landauer");
+    /// let remove_fut = async { map.remove("Gustav Landauer") };
+    /// let (removed, _) = removed_fut.join(remove_fut).await;
+    /// assert!(removed);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_removed<'b, Q: ?Sized + Hash + Eq>(&self, qey: &'b Q)
+        -> WaitRemoved<'_, K, V, S>
+    where
+        K: From<&'b Q> + Clone + Send + 'static,
+    {
+        WaitRemoved::new(self, K::from(qey))
+    }
+
+    /// Makes room for `n` more tasks to wait on a key without reallocating.
+    ///
+    /// Each key has room for one waiting task before its list of waiters has to be moved to
+    /// the heap. For keys which are expected to have many waiters, this allocates room for all
+    /// of them at once. Like `wait`, this puts a placeholder for the key into the map if it is
+    /// missing. If the key is already filled, this does nothing.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.reserve_wait_capacity("Ricardo Mella", 16);
+    /// assert!(map.contains_waiting("Ricardo Mella"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reserve_wait_capacity<'b, Q: ?Sized + Hash + Eq>(&self, key: &'b Q, n: usize)
+        where K: Borrow<Q> + From<&'b Q>
+    {
+        if self.is_closed() { return; }
+        let mut entry = self.map.entry(K::from(key)).or_insert(Waiting(WakerSet::new()));
+        if let Waiting(wakers) = entry.value_mut() {
+            wakers.reserve(n);
+        }
+    }
+
+    /// Waits for an owned key to be filled.
+    ///
+    /// This is the same as `wait`, but it looks the key up by the key type itself, so it does
+    /// not need `K` to be constructible from a borrowed query type. It needs `K: Clone`
+    /// instead, since it has to own two copies of the key: the future keeps one to look the key
+    /// up whenever it is polled, and the placeholder put into the map for a missing key needs
+    /// another. `wait` builds the placeholder's key from the query with `From`, which is why it
+    /// has no such bound. The key is cloned once.
+    ///
+    /// This is how to wait on composite keys such as tuples: they don't implement `From` for a
+    /// reference to themselves, so `map.wait(&(1, 2))` does not compile for a map keyed by
+    /// `(u32, u32)`. `wait` cannot drop that bound just for the case where the query type is
+    /// the key type without breaking the keys which are built from a different borrowed form.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<(u32, u32), &str> = WaitMap::new();
+    ///
+    /// let insert_fut = async { map.insert((1917, 10), "Petrograd") };
+    /// let wait_fut = map.wait_eq((1917, 10));
+    ///
+    /// let (_, value) = insert_fut.join(wait_fut).await;
+    /// assert_eq!(value.unwrap().value(), &"Petrograd");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_eq(&self, key: K) -> WaitEq<'_, K, V, S>
+        where K: Clone
+    {
+        let (_, reservation) = self.reserve_key(key.clone());
+        WaitEq::new(self, key, reservation)
+    }
+
+    /// Waits for a key to be filled through a weak reference to the map, resolving to a clone
+    /// of its value.
+    ///
+    /// The future only holds the map alive while it is being polled, so a task parked on it
+    /// does not stop the map from being dropped. If the map is dropped, the future evaluates
+    /// to `None`. Like `wait`, this puts a placeholder for the key into the map, if the map is
+    /// still alive.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let weak = Arc::downgrade(&map);
+    /// let handle = task::spawn(WaitMap::wait_weak(&weak, "Ret Marut".to_string()));
+    /// task::sleep(Duration::from_millis(100)).await;
+    ///
+    /// drop(map);
+    /// assert!(handle.await.is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_weak(map: &Weak<Self>, key: K) -> WaitWeak<K, V, S>
+        where K: Clone
+    {
+        let reservation = map.upgrade().and_then(|map| map.reserve_key(key.clone()).1);
+        WaitWeak::new(map.clone(), key, reservation)
+    }
+
+    /// Waits for a key to be filled, resolving to a mutable reference to its value.
+    ///
+    /// If the key is already filled, this resolves on its first poll. Checking the key and
+    /// registering the task as a waiter happen under the same lock, so unlike calling `get_mut`
+    /// and then waiting if it returns `None`, a value inserted in between cannot be missed.
+    /// Resolves to `None` if the key is cancelled or removed before it is filled.
+    ///
+    /// When a key is filled, every task waiting on it is woken at once, whether it called
+    /// `wait` or `wait_mut`, and they get at the value in whatever order the executor polls
+    /// them. No preference is given to either kind: a `wait_mut` resolves only once no guard
+    /// into the key's shard is held, so it can be held up by shared guards from `wait` calls
+    /// which were polled first. Keeping guards short-lived keeps this delay short.
+    ///
+    /// There is no mode preferring writers, because a filled key keeps no record of who was
+    /// woken when it was filled: the wakers it wakes are consumed, and the ones it keeps,
+    /// those `insert_wake_n` holds back, have not been woken yet. A later `wait` has no way to
+    /// tell that a `wait_mut` on the key has been woken but not resolved yet.
+    ///
+    /// Nor is there a mode handing the write guard to `wait_mut` callers one at a time, in the
+    /// order they started waiting. A filled key can keep waiters parked, as `insert_wake_n`
+    /// does with the ones it holds back, but those are only woken once the value is replaced
+    /// or removed: dropping a `RefMut` wakes no one, so there is nothing to pass the guard on
+    /// to the next waiter. To wake a key's waiters oldest first, use `insert_wake_n`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<&str>> = WaitMap::new();
+    ///
+    /// let insert_fut = async { map.insert("Mollie Steimer".to_string(), vec![]) };
+    /// let wait_fut = async {
+    ///     map.wait_mut("Mollie Steimer").await.unwrap().push("Fighters for Anarchism");
+    /// };
+    /// insert_fut.join(wait_fut).await;
+    ///
+    /// map.wait_mut("Mollie Steimer").await.unwrap().push("Senya Fleshin");
+    /// assert_eq!(map.get("Mollie Steimer").unwrap().len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_mut<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> impl FusedFuture<Output = Option<RefMut<'a, K, V, S>>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        WaitMut::new(self, qey, reservation)
+    }
+
+    /// Waits for a key to hold a value which an asynchronous predicate accepts, resolving to a
+    /// clone of that value.
+    ///
+    /// Once the key is filled, its value is cloned and passed to `pred`, without holding any
+    /// guard into the map while the predicate's future runs. If the predicate returns `false`,
+    /// this waits for the key to be filled again, for example by another `insert`, and checks
+    /// its new value. A value filled while the predicate is running is not missed. Resolves to
+    /// `None` if the key is cancelled or removed, including after a value has been rejected, or
+    /// if the map's waits are cancelled by `cancel_all` or a shutdown while it waits.
+    ///
+    /// After a rejection the task waits on the key itself, like `wait` does, and is woken once
+    /// the value is replaced or removed.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Peter Kropotkin".to_string(), 1);
+    ///
+    /// let wait_fut = map.wait_while_async("Peter Kropotkin", |count| async move { count > 2 });
+    /// let insert_fut = async {
+    ///     for count in 2..=3 {
+    ///         async_std::task::yield_now().await;
+    ///         map.insert("Peter Kropotkin".to_string(), count);
+    ///     }
+    /// };
+    /// let (accepted, _) = wait_fut.join(insert_fut).await;
+    /// assert_eq!(accepted, Some(3));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_while_async<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq, Fut>(
+        &'a self,
+        qey: &'b Q,
+        pred: impl Fn(V) -> Fut + 'f,
+    ) -> impl Future<Output = Option<V>> + 'f
+    where
+        Fut: Future<Output = bool>,
+        K: Borrow<Q> + From<&'b Q>,
+        V: Clone,
+    {
+        let wait = self.wait(qey);
+        async move {
+            drop(wait.await?);
+            // NB: the task registers with the key under the lock its value is read under, so a
+            // value filled while the predicate runs wakes it up again.
+            let mut refill = Refill::new(self, qey);
+            loop {
+                let value = poll_fn(|ctx| Poll::Ready(refill.read(ctx))).await?;
+                if pred(value.clone()).await {
+                    return Some(value);
+                }
+                if !poll_fn(|ctx| refill.poll_replaced(ctx)).await {
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Waits up to `dur` for a key to be filled, resolving to the result and how long it
+    /// waited.
+    ///
+    /// The result is `None` if the wait timed out or was cancelled. The elapsed time is
+    /// measured from when the future is first polled, which is useful for feeding backoff or
+    /// logging slow waits. If the wait times out and no other task is waiting on the key, the
+    /// key's placeholder is removed from the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let (entry, elapsed) = map.wait_timed("Ret Marut", Duration::from_millis(20)).await;
+    /// assert!(entry.is_none());
+    /// assert!(elapsed >= Duration::from_millis(20));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_timed<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(
+        &'a self,
+        qey: &'b Q,
+        dur: Duration,
+    ) -> impl Future<Output = (Option<Ref<'a, K, V, S>>, Duration)> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        let wait = Wait::new(self, qey, reservation);
+        async move {
+            let start = Instant::now();
+            let output = timeout(wait, dur).await.flatten();
+            (output, start.elapsed())
+        }
+    }
+
+    /// Waits for a key to be filled, giving up if the `cancel` future completes first.
+    ///
+    /// The future evaluates to `None` if `cancel` completes before the key is filled, or if the
+    /// wait is cancelled. Any future can be used to give up on the wait: a timer, a shutdown
+    /// signal or a cancellation token from another library, so the map does not need to know
+    /// about them. Whichever side wins, the wait stops waiting on the key once it resolves,
+    /// and if no other task is waiting on the key, its placeholder is removed from the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, future};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let shutdown = async {};
+    /// assert!(map.wait_until_or("Buenaventura Durruti", shutdown).await.is_none());
+    ///
+    /// map.insert("Buenaventura Durruti".to_string(), 1896);
+    /// let never = future::pending::<()>();
+    /// let entry = map.wait_until_or("Buenaventura Durruti", never).await;
+    /// assert_eq!(entry.unwrap().value(), &1896);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_until_or<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq, C: Future + 'f>(
+        &'a self,
+        qey: &'b Q,
+        cancel: C,
+    ) -> impl Future<Output = Option<Ref<'a, K, V, S>>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        let wait = Wait::new(self, qey, reservation);
+        async move {
+            let cancel = pin!(cancel);
+            Until::new(wait, cancel).await.flatten()
+        }
+    }
+
+    /// Waits up to `dur` for a key to be filled, filling it with the default value if it is not.
+    ///
+    /// If the wait times out or is cancelled, the key is filled with `V::default()` (waking
+    /// any other tasks waiting on it), unless another task has filled it in the meantime. This
+    /// check and fill happens under the shard lock, so concurrent timeouts on the same key
+    /// fill it only once. Either way, this evaluates to an exclusive reference to the value.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, Vec<i32>> = WaitMap::new();
+    ///
+    /// let mut entry = map.wait_mut_or_insert_default("Louise Michel", Duration::from_millis(10))
+    ///     .await;
+    /// entry.value_mut().push(1871);
+    /// drop(entry);
+    ///
+    /// assert_eq!(map.get("Louise Michel").unwrap().value(), &vec![1871]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_mut_or_insert_default<'a, 'b, Q: ?Sized + Hash + Eq>(
+        &'a self,
+        qey: &'b Q,
+        dur: Duration,
+    ) -> WaitMutOrDefault<'a, 'b, K, V, S, Q>
+    where
+        K: Borrow<Q> + From<&'b Q>,
+        V: Default,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        WaitMutOrDefault::new(self, qey, timeout(WaitMut::new(self, qey, reservation), dur))
+    }
+
+    /// Polls whether a key has been filled, for use when implementing futures by hand.
+    ///
+    /// This does one check of the key, the same as polling the future returned by `wait`:
+    ///
+    /// - If the key is filled, this returns `Ready(Some(_))`.
+    /// - If the key is waiting to be filled, the context's waker is registered with it and this
+    ///   returns `Pending`.
+    /// - If the key is missing, this returns `Ready(None)`.
+    ///
+    /// Unlike `wait`, this never puts a placeholder into the map. The `slot` records where the
+    /// waker was registered: pass the same slot, starting from `None`, every time the same
+    /// future polls, and pass it to `deregister` if that future is dropped before this returns
+    /// `Ready`. The slot is reset to `None` once this returns `Ready`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::future::poll_fn;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Lucía Sánchez Saornil".to_string(), 0);
+    ///
+    /// let mut slot = None;
+    /// let value = poll_fn(|ctx| {
+    ///     map.poll_get("Lucía Sánchez Saornil", ctx, &mut slot).map(|r| r.map(|r| *r.value()))
+    /// }).await;
+    /// assert_eq!(value, Some(0));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn poll_get<Q: ?Sized + Hash + Eq>(
+        &self,
+        key: &Q,
+        ctx: &mut Context<'_>,
+        slot: &mut Option<usize>,
+    ) -> Poll<Option<Ref<'_, K, V, S>>>
+        where K: Borrow<Q>
+    {
+        let mut idx = slot.unwrap_or(usize::MAX);
+        let poll = self.poll_ref(key, &mut idx, ctx, None);
+        *slot = if idx == usize::MAX { None } else { Some(idx) };
+        poll
+    }
+
+    /// Removes the waker registered by `poll_get`, if it has one.
+    pub fn deregister<Q: ?Sized + Hash + Eq>(&self, key: &Q, slot: &mut Option<usize>)
+        where K: Borrow<Q>
+    {
+        if let Some(idx) = slot.take() {
+            self.remove_waker(key, idx);
+        }
+    }
+
+    /// Checks once whether a key has been filled, for code which is not running in a task.
+    ///
+    /// - If the key is filled, this returns `Ready(Some(entry))`.
+    /// - If the key is waiting to be filled, this returns `Pending`.
+    /// - If the key is missing, this returns `Ready(None)`.
+    ///
+    /// Unlike `poll_get`, this needs no `Context`: no waker is registered, so nothing is woken
+    /// when the key is filled, and no placeholder is put into the map. It is `get`, except that
+    /// it tells a key which is waiting apart from one which is missing.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::task::Poll;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// assert!(matches!(map.try_wait_now("Francisco Ascaso"), Poll::Ready(None)));
+    ///
+    /// let _wait_fut = map.wait("Francisco Ascaso");
+    /// assert!(map.try_wait_now("Francisco Ascaso").is_pending());
+    ///
+    /// map.insert("Francisco Ascaso".to_string(), 1901);
+    /// match map.try_wait_now("Francisco Ascaso") {
+    ///     Poll::Ready(Some(entry))    => assert_eq!(entry.value(), &1901),
+    ///     _                           => unreachable!(),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_wait_now<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Poll<Option<Ref<'_, K, V, S>>>
+        where K: Borrow<Q>
+    {
+        match self.map.get(key) {
+            Some(entry) if matches!(entry.value(), Filled(..))  => {
+                Poll::Ready(Some(self.make_ref(entry)))
+            }
+            Some(_)                                             => Poll::Pending,
+            None                                                => Poll::Ready(None),
+        }
+    }
+
+    /// Polls whether each of several keys has been filled, returning how many of them are still
+    /// waiting to be filled.
+    ///
+    /// This does one `poll_get` for each key, with `slots[i]` as the slot for `keys[i]`, so the
+    /// same contract applies to every slot: pass the same slots every time the same future
+    /// polls, and if that future is dropped while any of them is `Some`, pass each key and its
+    /// slot to `deregister`. A key counts as done once it is filled or missing; its slot is
+    /// then `None`. No guard is held once this returns, so a future waiting for all of the keys
+    /// should look their values up once this returns `0`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` and `slots` have different lengths.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::future::poll_fn;
+    /// # use std::task::Poll;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Bakunin".to_string(), 0);
+    /// map.reserve_all(vec!["Kropotkin".to_string()]);
+    ///
+    /// let keys = ["Bakunin", "Kropotkin"];
+    /// let mut slots = [None, None];
+    /// let pending = poll_fn(|ctx| Poll::Ready(map.poll_wait_all(&keys, ctx, &mut slots))).await;
+    /// assert_eq!(pending, 1);
+    /// assert!(slots[0].is_none() && slots[1].is_some());
+    ///
+    /// for (key, slot) in keys.iter().zip(&mut slots) {
+    ///     map.deregister(*key, slot);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn poll_wait_all<Q: ?Sized + Hash + Eq>(
+        &self,
+        keys: &[&Q],
+        ctx: &mut Context<'_>,
+        slots: &mut [Option<usize>],
+    ) -> usize
+        where K: Borrow<Q>
+    {
+        assert_eq!(keys.len(), slots.len(), "every key needs a slot");
+        let mut pending = 0;
+        for (&key, slot) in keys.iter().zip(slots) {
+            if self.poll_get(key, ctx, slot).is_pending() {
+                pending += 1;
+            }
+        }
+        pending
+    }
+
+    /// Waits for a key to be filled, then removes it from the map, returning the key-value pair.
+    ///
+    /// If the key is already filled, it is removed immediately. If several tasks are waiting to
+    /// take the same key, the value is handed off to exactly one of them; the others evaluate
+    /// to `None`, just as if the wait had been cancelled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let insert_fut = async { map.insert("Ricardo Flores Magón".to_string(), 0) };
+    /// let take_fut = map.wait_take("Ricardo Flores Magón");
+    ///
+    /// let (_, taken) = insert_fut.join(take_fut).await;
+    /// assert_eq!(taken, Some(("Ricardo Flores Magón".to_string(), 0)));
+    /// assert!(map.get("Ricardo Flores Magón").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_take<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> WaitTake<'a, 'b, K, V, S, Q>
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        WaitTake::new(self, qey, reservation)
+    }
+
+    /// Waits for a key to be filled, then removes it from the map, like `wait_take`, but reports
+    /// why the value was not received if it was not.
+    ///
+    /// When several tasks are waiting to take the same key, the one which receives the value
+    /// evaluates to `RemoveOutcome::Removed`. The others evaluate to `RemoveOutcome::Lost`,
+    /// rather than the `None` that `wait_take` gives them, which tells them apart from waits
+    /// whose key was cancelled or removed without being filled, which evaluate to
+    /// `RemoveOutcome::KeyGone`. A task can only tell that it lost the race if it was already
+    /// waiting when the key was filled; if the value was taken before it was first polled, it
+    /// evaluates to `RemoveOutcome::KeyGone`.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use std::future::poll_fn;
+    /// # use std::task::Poll;
+    /// # use waitmap::{RemoveOutcome, WaitMap};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let mut slow = Box::pin(map.wait_take_detailed("Nestor Makhno"));
+    /// assert!(poll_fn(|ctx| Poll::Ready(slow.as_mut().poll(ctx).is_pending())).await);
+    ///
+    /// map.insert("Nestor Makhno".to_string(), 1888);
+    /// let fast = map.wait_take_detailed("Nestor Makhno").await;
+    /// assert_eq!(fast, RemoveOutcome::Removed(("Nestor Makhno".to_string(), 1888)));
+    /// assert_eq!(slow.await, RemoveOutcome::Lost);
+    ///
+    /// let gone = map.wait_take_detailed("Voltairine de Cleyre");
+    /// map.cancel("Voltairine de Cleyre");
+    /// assert_eq!(gone.await, RemoveOutcome::KeyGone);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_take_detailed<'a, 'b, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> WaitTakeDetailed<'a, 'b, K, V, S, Q>
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let (_, reservation) = self.reserve_key(K::from(qey));
+        WaitTakeDetailed::new(self, qey, reservation)
+    }
+
+    /// Waits for a key to be filled, then removes it from the map, like `wait_take`.
+    ///
+    /// The returned future owns a clone of the `Arc` and the key, so it does not borrow the map
+    /// and can be spawned onto another task, making this a convenient single-consumer hand-off
+    /// between tasks. It evaluates to `None` if the wait is cancelled.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let handle = task::spawn(map.wait_owned_take("Gaston Leval".to_string()));
+    /// map.insert("Gaston Leval".to_string(), 1895);
+    /// assert_eq!(handle.await, Some(("Gaston Leval".to_string(), 1895)));
+    /// assert!(map.get("Gaston Leval").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_owned_take(self: &Arc<Self>, key: K) -> WaitOwnedTake<K, V, S>
+        where K: Clone
+    {
+        let (_, reservation) = self.reserve_key(key.clone());
+        WaitOwnedTake::new(self.clone(), key, reservation)
+    }
+
+    /// Removes a key from the map, returning its value if it was filled.
+    ///
+    /// If the key was waiting to be filled, the entry is removed anyway and any pending `wait`
+    /// calls for this key are woken up, evaluating to `None`.
+    ///
+    /// A `wait` only receives a value if it is polled while its key is filled. Tasks waiting on
+    /// a key are woken when it is filled, but if the key is removed before a woken task gets to
+    /// poll its `wait` again, that `wait` evaluates to `None` too. Once a key has been filled
+    /// the map no longer tracks who was waiting on it, so a removed value cannot be handed on
+    /// to them; use `wait_take` when a value must reach exactly one consumer.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Lucy Parsons".to_string(), 0);
+    ///
+    /// assert_eq!(map.remove("Lucy Parsons"), Some(0));
+    /// assert!(map.get("Lucy Parsons").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>
+    {
+        match self.map.remove(key)? {
+            (key, Filled(value, _)) => {
+                self.count_removal(&key);
+                self.hooks.removed(&key, &value);
+                Some(value)
+            }
+            (key, Waiting(wakers))  => {
+                wakers.wake_removed();
+                self.hooks.cancelled(&key);
+                None
+            }
+        }
+    }
+
+    /// Takes the value out of a filled key, returning it.
+    ///
+    /// Unlike `remove`, this never disturbs pending waits: if the key is waiting to be filled,
+    /// the entry is left in place and `None` is returned. The three removal operations differ
+    /// in which entries they touch:
+    ///
+    /// - `remove` removes the entry whatever its state, cancelling any pending waits.
+    /// - `cancel` only removes entries which are waiting, cancelling their pending waits.
+    /// - `take` only removes entries which are filled, leaving pending waits alone.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Lucy Parsons".to_string(), 0);
+    /// let wait_fut = map.wait("Louise Michel");
+    ///
+    /// assert_eq!(map.take("Lucy Parsons"), Some(0));
+    /// assert_eq!(map.take("Louise Michel"), None);
+    ///
+    /// map.insert("Louise Michel".to_string(), 1);
+    /// assert_eq!(wait_fut.await.unwrap().value(), &1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn take<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>
+    {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Removes a filled key from the map, returning the key and its value.
+    ///
+    /// Like `take`, this leaves keys which are waiting to be filled in place and returns `None`
+    /// for them, so it never disturbs pending waits.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Johann Most".to_string(), 0);
+    /// let wait_fut = map.wait("Alexander Berkman");
+    ///
+    /// assert_eq!(map.remove_entry("Johann Most"), Some(("Johann Most".to_string(), 0)));
+    /// assert_eq!(map.remove_entry("Alexander Berkman"), None);
+    /// assert!(map.contains_waiting("Alexander Berkman"));
+    /// # drop(wait_fut);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_entry<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>
+    {
+        match self.map.remove_if(key, |_, entry| matches!(entry, Filled(..)))? {
+            (key, Filled(value, _)) => {
+                self.count_removal(&key);
+                self.hooks.removed(&key, &value);
+                Some((key, value))
+            }
+            (_, Waiting(_))         => unreachable!(),
+        }
+    }
+
+    /// Removes every filled entry whose key matches a predicate, returning how many were
+    /// removed.
+    ///
+    /// Entries which are waiting to be filled are left alone, whether or not their key matches.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("tenant-a/1".to_string(), 0);
+    /// map.insert("tenant-b/1".to_string(), 1);
+    ///
+    /// assert_eq!(map.remove_matching(|key| key.starts_with("tenant-a/")), 1);
+    /// assert!(map.get("tenant-a/1").is_none());
+    /// assert!(map.get("tenant-b/1").is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn remove_matching(&self, mut pred: impl FnMut(&K) -> bool) -> usize {
+        let mut count = 0;
+        for shard in self.map.shards() {
+            let removed = extract_matching(&mut shard.write(), |key, entry| {
+                matches!(entry, Filled(..)) && pred(key)
+            });
+
+            count += removed.len();
+            for (key, entry) in removed {
+                if let Filled(value, _) = entry {
+                    self.count_removal(&key);
+                    self.hooks.removed(&key, &value);
+                }
+            }
+        }
+        count
+    }
+
+    /// Removes every filled entry which matches a predicate, returning the removed pairs.
+    ///
+    /// Unlike `remove_matching`, the predicate sees the value as well as the key. Entries which
+    /// are waiting to be filled are left alone. Each shard is write locked once while the
+    /// predicate is applied to its entries.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Paris".to_string(), 1871);
+    /// map.insert("Barcelona".to_string(), 1936);
+    ///
+    /// let extracted = map.extract_if(|_, year| *year > 1900);
+    /// assert_eq!(extracted, vec![("Barcelona".to_string(), 1936)]);
+    /// assert!(map.get("Paris").is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extract_if(&self, mut pred: impl FnMut(&K, &V) -> bool) -> Vec<(K, V)> {
+        let mut extracted = vec![];
+        for shard in self.map.shards() {
+            let removed = extract_matching(&mut shard.write(), |key, entry| match entry {
+                Filled(value, _) => pred(key, value),
+                Waiting(_)       => false,
+            });
+
+            for (key, entry) in removed {
+                if let Filled(value, _) = entry {
+                    self.count_removal(&key);
+                    self.hooks.removed(&key, &value);
+                    extracted.push((key, value));
+                }
+            }
+        }
+        extracted
+    }
+
+    pub fn cancel<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool 
+        where K: Borrow<Q>
+    {
+        if let Some((key, entry)) = self.map.remove_if(key, |_, entry| {
+            matches!(entry, Waiting(_))
+        }) {
+            if let Waiting(wakers) = entry {
+                wakers.wake_removed();
+            }
+            self.hooks.cancelled(&key);
+            true
+        } else { false }
+    }
+
+    /// Cancels the pending `wait` calls on a key, but keeps it waiting to be filled.
+    ///
+    /// The cancelled waits evaluate to `None`, as they would after `cancel`, but the key's
+    /// placeholder stays in the map, so later calls to `wait` will wait for it to be filled.
+    /// Returns the number of tasks woken up, which only includes tasks that have polled their
+    /// `wait` at least once. If the key is missing or filled, this does nothing and returns 0.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let map1 = map.clone();
+    /// let handle = task::spawn(async move { map1.wait("Nestor Makhno").await.is_none() });
+    /// task::sleep(Duration::from_millis(100)).await;
+    ///
+    /// assert_eq!(map.cancel_waiters("Nestor Makhno"), 1);
+    /// assert!(handle.await);
+    /// assert!(map.contains_waiting("Nestor Makhno"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cancel_waiters<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> usize
+        where K: Borrow<Q>
+    {
+        let mut entry = match self.map.get_mut(key) {
+            Some(entry) => entry,
+            None        => return 0,
+        };
+        let cancelled = match entry.value_mut() {
+            Waiting(wakers) => wakers.cancel(),
+            Filled(..)      => return 0,
+        };
+        drop(entry); // drop early to release lock before waking other tasks
+        let woken = cancelled.len();
+        cancelled.wake();
+        woken
+    }
+
+    /// Cancels all outstanding `waits` on the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, stream, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// let mut waitstream =
+    ///     stream::from_iter(vec![map.wait("we"), map.wait("are"), map.wait("waiting")]);
+    ///
+    /// map.cancel_all();
+    ///
+    /// let mut num_cancelled = 0;
+    /// while let Some(wait_fut) = waitstream.next().await {
+    ///     assert!(wait_fut.await.is_none());
+    ///     num_cancelled += 1;
+    /// }
+    ///
+    /// assert!(num_cancelled == 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cancel_all(&self) {
+        self.cancel_waiting(None);
+    }
+
+    /// Cancels all outstanding `waits` on the map, returning the keys which were waiting to be
+    /// filled.
+    ///
+    /// This is `cancel_all` for shutdowns which want to report what was abandoned. Every key
+    /// with a placeholder in the map is returned, including keys reserved with `reserve_all`
+    /// which no task had started waiting on yet.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Sacco".to_string(), 1891);
+    /// let wait_fut = map.wait("Vanzetti");
+    ///
+    /// assert_eq!(map.drain_waiting(), vec!["Vanzetti".to_string()]);
+    /// assert!(wait_fut.await.is_none());
+    /// assert!(map.get("Sacco").is_some());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_waiting(&self) -> Vec<K> {
+        let mut keys = vec![];
+        self.cancel_waiting(Some(&mut keys));
+        keys
+    }
+
+    /// Shuts the map down, giving pending `wait` calls up to `grace` to be filled before they
+    /// are cancelled.
+    ///
+    /// The map stops accepting new waits as soon as this is called: placeholders are no longer
+    /// put into the map, and `wait` calls which have not yet been polled evaluate to `None`
+    /// instead of waiting. Waits which were already waiting can still be filled as normal
+    /// during the grace period. Once it is over, every key which is still waiting to be filled
+    /// is cancelled, and the returned future evaluates to the number of waiters which were
+    /// cancelled. Only waiters which have polled at least once are counted.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    ///
+    /// let map1 = map.clone();
+    /// let served = task::spawn(async move { map1.wait("Lucy Parsons").await.is_some() });
+    /// let map2 = map.clone();
+    /// let abandoned = task::spawn(async move { map2.wait("Albert Parsons").await.is_none() });
+    /// task::sleep(Duration::from_millis(100)).await;
+    ///
+    /// let shutdown = map.shutdown_graceful(Duration::from_millis(100));
+    /// assert!(map.wait("Voltairine de Cleyre").await.is_none());
+    /// map.insert("Lucy Parsons".to_string(), 0);
+    ///
+    /// assert_eq!(shutdown.await, 1);
+    /// assert!(served.await);
+    /// assert!(abandoned.await);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shutdown_graceful(&self, grace: Duration) -> impl Future<Output = usize> + '_ {
+        self.closed.store(true, Ordering::SeqCst);
+        async move {
+            Delay::new(grace).await;
+            self.cancel_waiting(None)
+        }
+    }
+
+    // Cancels every key which is waiting to be filled, returning the number of waiters woken
+    // and collecting the cancelled keys into `keys` if it is given.
+    fn cancel_waiting(&self, mut keys: Option<&mut Vec<K>>) -> usize {
+        self.listeners.cancel();
+        self.removals.cancel();
+        let mut woken = 0;
+        for shard in self.map.shards() {
+            let mut shard = shard.write();
+            let cancelled = extract_matching(&mut shard, |_, entry| matches!(entry, Waiting(_)));
+            // the waiters `insert_wake_n` held back are woken too, and see the filled value; the
+            // set is left with the key, marked as cancelled, so that a `wait_while_async` which
+            // was waiting for the value to be replaced can tell it was cancelled instead
+            let held_back: Vec<_> = shard.values_mut().filter_map(|entry| match entry.get_mut() {
+                Filled(_, held_back)    => held_back.as_mut().map(|wakers| wakers.cancel()),
+                Waiting(_)              => None,
+            }).collect();
+            drop(shard);
+
+            // NB: the shard's guard has been dropped by now, so woken tasks will see the
+            // entries as missing rather than blocking on the lock.
+            for wakers in held_back {
+                woken += wakers.len();
+                wakers.wake();
+            }
+            for (key, entry) in cancelled {
+                if let Waiting(wakers) = entry {
+                    woken += wakers.len();
+                    wakers.wake_removed();
+                }
+                self.hooks.cancelled(&key);
+                if let Some(keys) = keys.as_mut() {
+                    keys.push(key);
+                }
+            }
+        }
+        woken
+    }
+
+    /// Removes every entry from the map, returning the filled key-value pairs and the number of
+    /// waiters that were cancelled.
+    ///
+    /// Only waiters that have been polled at least once are counted.
+    ///
+    /// This is a one-shot teardown: the pairs can be persisted before shutting down, and the
+    /// count says how many pending `wait` calls were abandoned. The map is drained one shard at
+    /// a time, and the cancelled waiters of each shard are woken after its lock is released.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, task};
+    /// # use std::sync::Arc;
+    /// # use std::time::Duration;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    /// map.insert("Ricardo Flores Magón".to_string(), 0);
+    ///
+    /// let handles: Vec<_> = (0..2).map(|_| {
+    ///     let map = map.clone();
+    ///     task::spawn(async move { map.wait("Praxedis Guerrero").await.is_none() })
+    /// }).collect();
+    /// task::sleep(Duration::from_millis(100)).await;
+    ///
+    /// let (drained, cancelled) = map.take_all();
+    /// assert_eq!(drained, vec![("Ricardo Flores Magón".to_string(), 0)]);
+    /// assert_eq!(cancelled, 2);
+    /// for handle in handles {
+    ///     assert!(handle.await);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn take_all(&self) -> (Vec<(K, V)>, usize) {
+        let mut drained = vec![];
+        let mut cancelled = 0;
+
+        for shard in self.map.shards() {
+            let entries: Vec<_> = shard.write().drain().collect();
+
+            for (key, entry) in entries {
+                match entry.into_inner() {
+                    Filled(value, _) => {
+                        self.count_removal(&key);
+                        self.hooks.removed(&key, &value);
+                        drained.push((key, value));
+                    }
+                    Waiting(wakers)  => {
+                        cancelled += wakers.len();
+                        wakers.wake_removed();
+                        self.hooks.cancelled(&key);
+                    }
+                }
+            }
+        }
+
+        (drained, cancelled)
+    }
+
+    /// Removes every entry from the map, yielding the filled key-value pairs as a stream.
+    ///
+    /// The map is drained one shard at a time, and the stream yields to the executor between
+    /// shards so that draining a large map does not stall other tasks. Any pending `wait` calls
+    /// are cancelled as their shard is drained.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Nestor Makhno".to_string(), 0);
+    /// map.insert("Maria Nikiforova".to_string(), 1);
+    ///
+    /// let mut drained: Vec<(String, i32)> = map.drain_stream().collect().await;
+    /// drained.sort();
+    /// assert_eq!(drained, vec![
+    ///     ("Maria Nikiforova".to_string(), 1),
+    ///     ("Nestor Makhno".to_string(), 0),
+    /// ]);
+    /// assert!(map.get("Nestor Makhno").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_stream(&self) -> impl Stream<Item = (K, V)> + '_ {
+        DrainStream::new(self)
+    }
+
+    /// Inserts every key-value pair from a stream into the map, evaluating to the number of
+    /// pairs inserted.
+    ///
+    /// Each pair is inserted as by `insert`, waking any pending `wait` calls for its key. The
+    /// future yields to the executor after each pair, so a stream which is always ready does
+    /// not stall other tasks.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, stream};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    ///
+    /// let pairs = vec![("Paris".to_string(), 1871), ("Kronstadt".to_string(), 1921)];
+    /// assert_eq!(map.extend_stream(stream::from_iter(pairs)).await, 2);
+    /// assert_eq!(map.get("Kronstadt").unwrap().value(), &1921);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn extend_stream<'a, St>(&'a self, stream: St) -> impl Future<Output = usize> + 'a
+        where St: Stream<Item = (K, V)> + 'a
+    {
+        ExtendStream::new(self, stream)
+    }
+
+    /// Converts the map into a read-only view of its filled entries.
+    ///
+    /// All outstanding `waits` on the map are cancelled first, so once the map is read-only
+    /// nothing can be waiting on it.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Buenaventura Durruti".to_string(), 0);
+    /// map.insert("Francisco Ascaso".to_string(), 1);
+    /// let wait_fut = map.wait("Juan García Oliver");
+    /// drop(wait_fut);
+    ///
+    /// let map = map.into_read_only();
+    /// assert_eq!(map.len(), 2);
+    /// assert_eq!(map.get("Buenaventura Durruti"), Some(&0));
+    /// assert!(map.get("Juan García Oliver").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn into_read_only(self) -> ReadOnlyWaitMap<K, V, S> {
+        self.cancel_all();
+        ReadOnlyWaitMap::new(self.map.into_read_only())
     }
-}
 
-impl<K: Hash + Eq, V, S: BuildHasher + Clone> WaitMap<K, V, S> {
-    /// Make a new `WaitMap` using a custom hasher.
+    /// Consumes the map, returning an iterator over the keys which were filled.
+    ///
+    /// All outstanding `waits` on the map are cancelled first, so keys which were waiting to
+    /// be filled are not yielded.
     /// ```
     /// # extern crate async_std;
     /// # extern crate waitmap;
     /// # use async_std::main;
     /// # use waitmap::WaitMap;
-    /// use std::collections::hash_map::RandomState;
     /// # #[async_std::main]
     /// # async fn main() -> std::io::Result<()> {
-    /// let map: WaitMap<i32, String> = WaitMap::with_hasher(RandomState::new());
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Francisco Ferrer".to_string(), 0);
+    /// map.reserve_all(vec!["Anselmo Lorenzo".to_string()]);
+    ///
+    /// let keys: Vec<String> = map.into_keys().collect();
+    /// assert_eq!(keys, vec!["Francisco Ferrer".to_string()]);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn with_hasher(hasher: S) -> WaitMap<K, V, S> {
-        WaitMap { map: DashMap::with_hasher(hasher) }
+    pub fn into_keys(self) -> impl Iterator<Item = K> {
+        self.into_filled().map(|(key, _)| key)
     }
 
-    /// Inserts a key-value pair into the map.
-    ///
-    /// If the map did not have this key present, `None` is returned.
+    /// Consumes the map, returning an iterator over the values of the keys which were filled.
     ///
-    /// If there are any pending `wait` calls for this key, they are woken up.
+    /// All outstanding `waits` on the map are cancelled first.
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.into_filled().map(|(_, value)| value)
+    }
+
+    /// Consumes the map, rebuilding it with a different hasher.
     ///
-    /// If the map did have this key present, the value is updated and the old value is returned.
+    /// The filled entries and the hooks are carried over to the new map. All outstanding `waits`
+    /// on the old map are cancelled, since their futures borrow it; tasks which want to keep
+    /// waiting have to wait on the new map instead.
     /// ```
     /// # extern crate async_std;
     /// # extern crate waitmap;
-    /// # use async_std::{main, sync::Arc, prelude::*};
+    /// # use async_std::main;
     /// # use waitmap::WaitMap;
+    /// use std::collections::hash_map::RandomState;
     /// # #[async_std::main]
     /// # async fn main() -> std::io::Result<()> {
-    /// let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
-    ///
-    /// let insert_fut = async { map.insert("hi".to_string(), 0) };
-    /// let wait_fut = map.wait("hi");
+    /// let map: WaitMap<String, i32> = WaitMap::new();
+    /// map.insert("Louise Michel".to_string(), 1871);
     ///
-    /// let (insert_res, wait_res) = insert_fut.join(wait_fut).await;
-    /// assert!(insert_res.is_none());
-    /// assert!(wait_res.is_some());
+    /// let map = map.rehash_with(RandomState::new());
+    /// assert_eq!(*map.get("Louise Michel").unwrap().value(), 1871);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn insert(&self, key: K, value: V) -> Option<V> {
-        match self.map.entry(key) {
-            Occupied(mut entry)  => {
-                match mem::replace(entry.get_mut(), Filled(value)) {
-                    Waiting(wakers) => {
-                        drop(entry); // drop early to release lock before waking other tasks
-                        wakers.wake();
-                        None
-                    }
-                    Filled(value)   => Some(value),
-                }
-            }
-            Vacant(slot)     => {
-                slot.insert(Filled(value));
-                None
+    pub fn rehash_with<S2: BuildHasher + Clone>(self, hasher: S2) -> WaitMap<K, V, S2> {
+        self.cancel_all();
+        let map = DashMap::with_capacity_and_hasher(self.map.len(), hasher);
+        for (key, entry) in self.map {
+            if let Filled(value, _) = entry {
+                map.insert(key, Filled(value, None));
             }
         }
+        WaitMap {
+            map,
+            hooks: self.hooks,
+            listeners: Listeners::new(),
+            removals: Listeners::new(),
+            closed: self.closed,
+            max_waiters: self.max_waiters,
+            filled: self.filled,
+            in_flight: self.in_flight,
+            #[cfg(feature = "guard-timing")]
+            guard_limit: self.guard_limit,
+        }
     }
 
-    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<Ref<'_, K, V, S>>
-        where K: Borrow<Q>
-    {
-        Some(Ref { inner: self.map.get(key)? })
+    fn into_filled(self) -> impl Iterator<Item = (K, V)> {
+        self.cancel_all();
+        self.map.into_iter().filter_map(|(key, entry)| match entry {
+            Filled(value, _) => Some((key, value)),
+            Waiting(_)       => None,
+        })
     }
 
-    pub fn get_mut<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<RefMut<'_, K, V, S>>
-        where K: Borrow<Q>
-    {
-        Some(RefMut { inner: self.map.get_mut(key)? })
+    /// Sets a callback to run whenever a value is inserted into the map.
+    ///
+    /// The callback runs just before the value is put into the map, so a callback which looks
+    /// the key up still sees its old value. Methods which are given the value, such as `insert`,
+    /// `fill` and `insert_sorted`, run it before the key's shard is locked, so it may access the
+    /// map itself. The entry API, `insert_with`, `insert_with_if_waiting` and
+    /// [`ShardView::insert`] only have the value once the shard is locked, and run the callback
+    /// while holding that lock, so there it must not access the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use std::sync::Arc;
+    /// # use std::sync::atomic::{AtomicUsize, Ordering};
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let inserts = Arc::new(AtomicUsize::new(0));
+    /// let counter = inserts.clone();
+    /// let map: WaitMap<String, i32> = WaitMap::new()
+    ///     .on_insert(move |_, _| { counter.fetch_add(1, Ordering::Relaxed); });
+    ///
+    /// map.insert("Alexander Berkman".to_string(), 0);
+    /// map.insert("Alexander Berkman".to_string(), 1);
+    /// assert_eq!(inserts.load(Ordering::Relaxed), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_insert(mut self, hook: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.hooks.set_on_insert(hook);
+        self
     }
 
-    pub fn wait<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
-        -> impl Future<Output = Option<Ref<'a, K, V, S>>> + 'f
-    where
-        K: Borrow<Q> + From<&'b Q>,
-    {
-        let key = K::from(qey);
-        self.map.entry(key).or_insert(Waiting(WakerSet::new()));
-        Wait::new(&self.map, qey)
+    /// Sets a callback to run whenever a filled value is removed from the map.
+    ///
+    /// The callback runs once the value has been taken out of the map and the shard's lock
+    /// released, so it may access the map itself, except for removals through an
+    /// [`OccupiedEntry`] or a [`ShardView`], which still hold the lock.
+    pub fn on_remove(mut self, hook: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        self.hooks.set_on_remove(hook);
+        self
     }
 
-    pub fn wait_mut<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
-        -> impl Future<Output = Option<RefMut<'a, K, V, S>>> + 'f
-    where
-        K: Borrow<Q> + From<&'b Q>,
-    {
-        let key = K::from(qey);
-        self.map.entry(key).or_insert(Waiting(WakerSet::new()));
-        WaitMut::new(&self.map, qey)
+    /// Sets a callback to run whenever a key which was waiting to be filled is cancelled.
+    ///
+    /// As with `on_remove`, the callback runs once the shard's lock is released, except for
+    /// keys removed through a [`ShardView`].
+    pub fn on_cancel(mut self, hook: impl Fn(&K) + Send + Sync + 'static) -> Self {
+        self.hooks.set_on_cancel(hook);
+        self
     }
 
-    pub fn cancel<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool 
-        where K: Borrow<Q>
-    {
-        if let Some((_, entry)) = self.map.remove_if(key, |_, entry| {
-            if let Waiting(_) = entry { true } else { false }
-        }) {
-            if let Waiting(wakers) = entry {
-                wakers.wake();
-            }
-            true
-        } else { false }
+    /// Sets a callback to run whenever a wait future which had to park is resolved, with how
+    /// long it was parked.
+    ///
+    /// If the key was filled, the outcome also says how many tasks were waiting on it at that
+    /// moment, which helps to find hot keys.
+    ///
+    /// The time is measured from when the future first registered its waker, so waits which
+    /// resolve on their first poll are not reported. This covers `wait`, `wait_mut`, `wait_eq`
+    /// and `wait_take`, and the futures built on them. The callback runs from within the
+    /// future's `poll`, while any guard it resolves to is held, so it must not access the map.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::{main, prelude::*};
+    /// # use std::sync::{Arc, Mutex};
+    /// # use waitmap::{WaitMap, WaitOutcome};
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let outcomes = Arc::new(Mutex::new(vec![]));
+    /// let recorded = outcomes.clone();
+    /// let map: WaitMap<String, i32> = WaitMap::new()
+    ///     .on_wait_resolved(move |_, outcome| recorded.lock().unwrap().push(outcome));
+    ///
+    /// let wait_fut = map.wait("Nestor Makhno");
+    /// let insert_fut = async { map.insert("Nestor Makhno".to_string(), 0) };
+    /// wait_fut.join(insert_fut).await;
+    /// assert_eq!(*outcomes.lock().unwrap(), vec![WaitOutcome::Filled { waiters: 1 }]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_wait_resolved(
+        mut self,
+        hook: impl Fn(Duration, WaitOutcome) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.set_on_wait_resolved(hook);
+        self
     }
 
-    /// Cancels all outstanding `waits` on the map.
+    /// Sets how long a `Ref` or `RefMut` into this map may be held before it is considered a
+    /// bug, such as a guard being held across an `await`.
+    ///
+    /// In debug builds, dropping a guard which was held longer than this limit panics. This
+    /// requires the `guard-timing` feature.
+    #[cfg(feature = "guard-timing")]
+    pub fn guard_limit(mut self, limit: Duration) -> Self {
+        self.guard_limit = Some(limit);
+        self
+    }
+
+    // Fills the key unless it is already filled, returning a guard to its value. Waiting tasks
+    // are woken while the guard is still held.
+    fn fill_ref(&self, key: K, value: impl FnOnce() -> V) -> RefMut<'_, K, V, S> {
+        self.entry(key).or_insert_with(value)
+    }
+}
+
+impl<K: Hash + Eq, T, S: BuildHasher + Clone> WaitMap<K, Arc<T>, S> {
+    /// Waits for a key to be filled, resolving to a clone of its `Arc`.
+    ///
+    /// Only the `Arc` is cloned, however large the value it points to, and the shard's lock is
+    /// released as soon as it has been cloned, so the result can be held for as long as needed
+    /// without blocking writers. It evaluates to `None` if the wait is cancelled.
     /// ```
     /// # extern crate async_std;
     /// # extern crate waitmap;
-    /// # use async_std::{main, stream, prelude::*};
+    /// # use async_std::{main, prelude::*};
+    /// # use std::sync::Arc;
     /// # use waitmap::WaitMap;
     /// # #[async_std::main]
     /// # async fn main() -> std::io::Result<()> {
-    /// let map: WaitMap<String, i32> = WaitMap::new();
-    /// let mut waitstream =
-    ///     stream::from_iter(vec![map.wait("we"), map.wait("are"), map.wait("waiting")]);
-    ///
-    /// map.cancel_all();
+    /// let map: WaitMap<String, Arc<Vec<&str>>> = WaitMap::new();
     ///
-    /// let mut num_cancelled = 0;
-    /// while let Some(wait_fut) = waitstream.next().await {
-    ///     assert!(wait_fut.await.is_none());
-    ///     num_cancelled += 1;
-    /// }
+    /// let wait_fut = map.wait_arc("Alexander Berkman");
+    /// let insert_fut = async {
+    ///     map.insert("Alexander Berkman".to_string(), Arc::new(vec!["Prison Memoirs"]))
+    /// };
     ///
-    /// assert!(num_cancelled == 3);
+    /// let (books, _) = wait_fut.join(insert_fut).await;
+    /// let books = books.unwrap();
+    /// map.remove("Alexander Berkman");
+    /// assert_eq!(*books, ["Prison Memoirs"]);
     /// # Ok(())
     /// # }
     /// ```
-    pub fn cancel_all(&self) {
-        self.map.retain(|_, entry| {
-            if let Waiting(wakers) = entry {
-                // NB: In theory, there is a deadlock risk: if a task is awoken before the
-                // retain is completed, it may see a waiting entry with an empty waker set,
-                // rather than a missing entry.
-                //
-                // However, this is prevented by the memory guards already present in DashMap.
-                // No other task will be able to view this entry until the guard on this shard
-                // has been dropped, which will not occur until this shard's unretained members
-                // have actually been removed.
-                mem::replace(wakers, WakerSet::new()).wake();
-                false
-            } else { true }
-        })
+    pub fn wait_arc<'a: 'f, 'b: 'f, 'f, Q: ?Sized + Hash + Eq>(&'a self, qey: &'b Q)
+        -> impl Future<Output = Option<Arc<T>>> + 'f
+    where
+        K: Borrow<Q> + From<&'b Q>,
+    {
+        let wait = self.wait(qey);
+        async move { wait.await.map(|entry| entry.value().clone()) }
+    }
+}
+
+impl<K, V, S> WaitMap<K, V, S> {
+    // Counts keys going from missing or waiting to filled.
+    fn count_fills(&self, n: usize) {
+        self.filled.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // Counts a filled key being removed, and tells the tasks waiting for it to be removed.
+    fn count_removal(&self, key: &K) {
+        self.filled.fetch_sub(1, Ordering::Relaxed);
+        self.removals.removed(key);
+    }
+
+    fn make_ref<'a>(&'a self, inner: one::Ref<'a, K, WaitEntry<V>, S>) -> Ref<'a, K, V, S> {
+        Ref {
+            inner,
+            _permit: None,
+            #[cfg(feature = "guard-timing")]
+            _timer: GuardTimer::start(self.guard_limit),
+        }
+    }
+
+    fn make_ref_mut<'a>(&'a self, inner: impl Into<GuardMut<'a, K, V, S>>)
+        -> RefMut<'a, K, V, S>
+    {
+        RefMut {
+            inner: inner.into(),
+            _permit: None,
+            #[cfg(feature = "guard-timing")]
+            _timer: GuardTimer::start(self.guard_limit),
+        }
+    }
+}
+
+impl<'a, K, V, S> HoldPermit<'a> for Ref<'a, K, V, S> {
+    fn hold(&mut self, permit: Permit<'a>) {
+        self._permit = Some(permit);
+    }
+}
+
+impl<'a, K, V, S> HoldPermit<'a> for RefMut<'a, K, V, S> {
+    fn hold(&mut self, permit: Permit<'a>) {
+        self._permit = Some(permit);
     }
 }
 
 enum WaitEntry<V> {
     Waiting(WakerSet),
-    Filled(V),
+    // the waiters `insert_wake_n` held back, if any, which are woken once the value is
+    // replaced or removed
+    Filled(V, Option<Box<WakerSet>>),
 }
 
 /// A shared reference to a `WaitMap` key-value pair.
@@ -251,8 +3137,54 @@ enum WaitEntry<V> {
 /// # Ok(())
 /// # }
 /// ```
+///
+/// `Ref` also dereferences to the value:
+/// ```
+/// # extern crate async_std;
+/// # extern crate waitmap;
+/// # use async_std::main;
+/// # use waitmap::{Ref, WaitMap};
+/// # #[async_std::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let map: WaitMap<String, String> = WaitMap::new();
+/// map.insert("Emma Goldman".to_string(), "Living My Life".to_string());
+///
+/// let kv: Ref<String, String, _> = map.get("Emma Goldman").unwrap();
+/// assert_eq!(kv.len(), 14);
+/// assert_eq!(*kv, "Living My Life");
+/// # Ok(())
+/// # }
+/// ```
+///
+/// It implements `AsRef` and `Borrow` for the value too, so it can be passed to generic code.
+/// A key which is still waiting to be filled has no `Ref` to pass, since `get` returns `None`
+/// for it:
+/// ```
+/// # extern crate async_std;
+/// # extern crate waitmap;
+/// # use async_std::main;
+/// # use waitmap::WaitMap;
+/// # #[async_std::main]
+/// # async fn main() -> std::io::Result<()> {
+/// fn shout(title: impl AsRef<String>) -> String {
+///     title.as_ref().to_uppercase()
+/// }
+///
+/// let map: WaitMap<String, String> = WaitMap::new();
+/// map.insert("Emma Goldman".to_string(), "Anarchism".to_string());
+/// assert_eq!(shout(map.get("Emma Goldman").unwrap()), "ANARCHISM");
+///
+/// let _wait_fut = map.wait("Alexander Berkman");
+/// assert!(map.get("Alexander Berkman").map(shout).is_none());
+/// # Ok(())
+/// # }
+/// ```
 pub struct Ref<'a, K, V, S> {
     inner: one::Ref<'a, K, WaitEntry<V>, S>,
+    // held by references resolved by a wait when the map limits how many may be held at once
+    _permit: Option<Permit<'a>>,
+    #[cfg(feature = "guard-timing")]
+    _timer: GuardTimer,
 }
 
 impl<'a, K: Eq + Hash, V, S: BuildHasher> Ref<'a, K, V, S> {
@@ -262,19 +3194,89 @@ impl<'a, K: Eq + Hash, V, S: BuildHasher> Ref<'a, K, V, S> {
 
     pub fn value(&self) -> &V {
         match self.inner.value() {
-            Filled(value)   => value,
-            _               => panic!()
+            Filled(value, _) => value,
+            _                => panic!()
         }
     }
 
     pub fn pair(&self) -> (&K, &V) {
         (self.key(), self.value())
     }
+
+    /// Returns the value, or `None` if the key is still waiting to be filled.
+    pub fn try_value(&self) -> Option<&V> {
+        match self.inner.value() {
+            Filled(value, _) => Some(value),
+            Waiting(_)       => None,
+        }
+    }
+
+    /// Returns the value, or `default` if the key is still waiting to be filled.
+    pub fn value_or<'s>(&'s self, default: &'s V) -> &'s V {
+        self.try_value().unwrap_or(default)
+    }
+}
+
+impl<'a, K: Eq + Hash + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug
+    for Ref<'a, K, V, S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_pair(f, "Ref", self.key(), self.try_value())
+    }
+}
+
+// NB: `get`, `get_mut` and the waits only hand out guards into filled keys, so the value is
+// always there to dereference.
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Deref for Ref<'a, K, V, S> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> AsRef<V> for Ref<'a, K, V, S> {
+    fn as_ref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Borrow<V> for Ref<'a, K, V, S> {
+    fn borrow(&self) -> &V {
+        self.value()
+    }
+}
+
+/// A shared reference to a `WaitMap` key which is waiting to be filled.
+pub struct WaitingRef<'a, K, V, S> {
+    inner: multiple::RefMulti<'a, K, WaitEntry<V>, S>,
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> WaitingRef<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    /// The number of tasks currently waiting on this key.
+    pub fn waiters(&self) -> usize {
+        match self.inner.value() {
+            Waiting(wakers) => wakers.len(),
+            _               => panic!()
+        }
+    }
+
+    pub fn pair(&self) -> (&K, usize) {
+        (self.key(), self.waiters())
+    }
 }
 
 /// An exclusive reference to a `WaitMap` key-value pair.
 pub struct RefMut<'a, K, V, S> {
-    inner: one::RefMut<'a, K, WaitEntry<V>, S>,
+    inner: GuardMut<'a, K, V, S>,
+    // held by references resolved by a wait when the map limits how many may be held at once
+    _permit: Option<Permit<'a>>,
+    #[cfg(feature = "guard-timing")]
+    _timer: GuardTimer,
 }
 
 impl<'a, K: Eq + Hash, V, S: BuildHasher> RefMut<'a, K, V, S> {
@@ -284,15 +3286,15 @@ impl<'a, K: Eq + Hash, V, S: BuildHasher> RefMut<'a, K, V, S> {
 
     pub fn value(&self) -> &V {
         match self.inner.value() {
-            Filled(value)   => value,
-            _               => panic!()
+            Filled(value, _) => value,
+            _                => panic!()
         }
     }
 
     pub fn value_mut(&mut self) -> &mut V {
         match self.inner.value_mut() {
-            Filled(value)   => value,
-            _               => panic!()
+            Filled(value, _) => value,
+            _                => panic!()
         }
     }
 
@@ -300,10 +3302,149 @@ impl<'a, K: Eq + Hash, V, S: BuildHasher> RefMut<'a, K, V, S> {
         (self.key(), self.value())
     }
 
+    /// Replaces the value, returning the old one, without releasing the guard.
+    ///
+    /// A `RefMut` only ever points at a filled key, since `get_mut` returns `None` for a key
+    /// which is still waiting to be filled, so there is always a value to replace.
+    /// ```
+    /// # extern crate async_std;
+    /// # extern crate waitmap;
+    /// # use async_std::main;
+    /// # use waitmap::WaitMap;
+    /// # #[async_std::main]
+    /// # async fn main() -> std::io::Result<()> {
+    /// let map: WaitMap<String, &str> = WaitMap::new();
+    /// map.insert("Lucy Parsons".to_string(), "The Alarm");
+    ///
+    /// let mut paper = map.get_mut("Lucy Parsons").unwrap();
+    /// assert_eq!(paper.replace("The Liberator"), "The Alarm");
+    /// assert_eq!(paper.value(), &"The Liberator");
+    /// drop(paper);
+    ///
+    /// let _wait_fut = map.wait("Albert Parsons");
+    /// assert!(map.get_mut("Albert Parsons").is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn replace(&mut self, value: V) -> V {
+        mem::replace(self.value_mut(), value)
+    }
+
     pub fn pair_mut(&mut self) -> (&K, &mut V) {
         match self.inner.pair_mut() {
-            (key, Filled(value))    => (key, value),
+            (key, Filled(value, _)) => (key, value),
             _                       => panic!(),
         }
     }
 }
+
+impl<'a, K: Eq + Hash + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug
+    for RefMut<'a, K, V, S>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self.inner.value() {
+            Filled(value, _) => Some(value),
+            Waiting(_)       => None,
+        };
+        debug_pair(f, "RefMut", self.key(), value)
+    }
+}
+
+// NB: a guard should never point at a key which is still waiting, but one is printed rather
+// than panicking on if it does, since `Debug` is mostly used while something is already wrong.
+fn debug_pair<K: fmt::Debug, V: fmt::Debug>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    key: &K,
+    value: Option<&V>,
+) -> fmt::Result {
+    let mut f = f.debug_struct(name);
+    f.field("key", key);
+    match value {
+        Some(value) => f.field("value", value),
+        None        => f.field("value", &format_args!("<waiting>")),
+    };
+    f.finish()
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Deref for RefMut<'a, K, V, S> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> DerefMut for RefMut<'a, K, V, S> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.value_mut()
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> AsRef<V> for RefMut<'a, K, V, S> {
+    fn as_ref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Borrow<V> for RefMut<'a, K, V, S> {
+    fn borrow(&self) -> &V {
+        self.value()
+    }
+}
+
+/// A clone-on-write reference to a `WaitMap` value.
+///
+/// Created by [`WaitMap::wait_cow`]. It starts out borrowing the value through a [`Ref`],
+/// holding the shard's read lock, and only clones the value, releasing the lock, once it is
+/// mutated through `to_mut`.
+pub enum CowRef<'a, K, V, S> {
+    Borrowed(Ref<'a, K, V, S>),
+    Owned(V),
+}
+
+impl<'a, K: Eq + Hash, V: Clone, S: BuildHasher> CowRef<'a, K, V, S> {
+    /// Returns a mutable reference to an owned copy of the value, cloning it out of the map
+    /// the first time this is called.
+    pub fn to_mut(&mut self) -> &mut V {
+        if let CowRef::Borrowed(guard) = self {
+            *self = CowRef::Owned(guard.value().clone());
+        }
+        match self {
+            CowRef::Owned(value)    => value,
+            CowRef::Borrowed(_)     => unreachable!(),
+        }
+    }
+
+    /// Returns an owned copy of the value, cloning it out of the map if it has not been
+    /// already.
+    pub fn into_owned(self) -> V {
+        match self {
+            CowRef::Borrowed(guard) => guard.value().clone(),
+            CowRef::Owned(value)    => value,
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Deref for CowRef<'a, K, V, S> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match self {
+            CowRef::Borrowed(guard) => guard.value(),
+            CowRef::Owned(value)    => value,
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> AsRef<V> for CowRef<'a, K, V, S> {
+    fn as_ref(&self) -> &V {
+        self
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher> Borrow<V> for CowRef<'a, K, V, S> {
+    fn borrow(&self) -> &V {
+        self
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Called with each key-value pair filled into the map, or with `None` when the map's waits are
+// cancelled. Returns `true` once it does not need to be called again.
+pub(crate) type Listener<K, V> = Box<dyn FnMut(Option<(&K, &V)>) -> bool + Send>;
+
+// Callbacks for waits which are not tied to a single key, and so cannot be woken through the
+// `WakerSet` of a key.
+pub(crate) struct Listeners<K, V> {
+    // the number of registered listeners, so that filling a key skips the lock while there
+    // are none
+    count: AtomicUsize,
+    list: Mutex<List<K, V>>,
+}
+
+struct List<K, V> {
+    next_id: usize,
+    listeners: Vec<(usize, Listener<K, V>)>,
+}
+
+impl<K, V> Listeners<K, V> {
+    pub(crate) fn new() -> Listeners<K, V> {
+        Listeners {
+            count: AtomicUsize::new(0),
+            list: Mutex::new(List { next_id: 0, listeners: Vec::new() }),
+        }
+    }
+
+    pub(crate) fn add(&self, listener: Listener<K, V>) -> usize {
+        let mut list = self.list.lock().unwrap();
+        let id = list.next_id;
+        list.next_id += 1;
+        list.listeners.push((id, listener));
+        self.count.store(list.listeners.len(), Ordering::SeqCst);
+        id
+    }
+
+    pub(crate) fn remove(&self, id: usize) {
+        let mut list = self.list.lock().unwrap();
+        list.listeners.retain(|(other, _)| *other != id);
+        self.count.store(list.listeners.len(), Ordering::SeqCst);
+    }
+
+    // NB: this must be called under the lock of the shard the key is being filled in, so that
+    // a wait which registers a listener and then looks through the map either finds the value
+    // or is called with it.
+    pub(crate) fn filled(&self, key: &K, value: &V) {
+        if self.count.load(Ordering::SeqCst) == 0 { return; }
+        let mut list = self.list.lock().unwrap();
+        list.listeners.retain_mut(|(_, listener)| !listener(Some((key, value))));
+        self.count.store(list.listeners.len(), Ordering::SeqCst);
+    }
+
+    pub(crate) fn cancel(&self) {
+        let mut list = self.list.lock().unwrap();
+        for (_, mut listener) in list.listeners.drain(..) {
+            listener(None);
+        }
+        self.count.store(0, Ordering::SeqCst);
+    }
+}
+
+impl<K> Listeners<K, ()> {
+    // NB: unlike `filled`, this does not need the shard lock: a wait for a removal registers
+    // its listener before checking the map, and a key is only passed here once it is gone.
+    pub(crate) fn removed(&self, key: &K) {
+        self.filled(key, &());
+    }
+}
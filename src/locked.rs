@@ -0,0 +1,148 @@
+use std::hash::{Hash, BuildHasher};
+use std::mem;
+
+use dashmap::SharedValue;
+use dashmap::lock::RwLockWriteGuard;
+use dashmap::mapref::one;
+
+use crate::WaitEntry;
+use crate::shard::Shard;
+
+// A key of a shard whose write lock was taken by hand, which is how `try_entry` gets an entry
+// without blocking: dashmap only builds its own entries and guards by taking the lock itself.
+//
+// The key is looked up in the shard again on each access. A missing key can't be put into the
+// shard without giving up the key needed to look it up, so a value for a missing key is held
+// here instead, and put into the shard when this is dropped, before the lock is released.
+pub(crate) struct LockedKey<'a, K, V, S> {
+    shard: RwLockWriteGuard<'a, Shard<K, V, S>>,
+    key: Option<K>,
+    pending: Option<WaitEntry<V>>,
+    // NB: kept as a function so that dropping this doesn't need `K: Hash + Eq`, which would
+    // otherwise have to be required by every type holding one, `RefMut` included.
+    put: fn(&mut Shard<K, V, S>, K, WaitEntry<V>),
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> LockedKey<'a, K, V, S> {
+    pub(crate) fn new(shard: RwLockWriteGuard<'a, Shard<K, V, S>>, key: K) -> Self {
+        LockedKey {
+            shard,
+            key: Some(key),
+            pending: None,
+            put: |shard, key, entry| {
+                shard.insert(key, SharedValue::new(entry));
+            },
+        }
+    }
+
+    pub(crate) fn key(&self) -> &K {
+        self.key.as_ref().unwrap()
+    }
+
+    pub(crate) fn get(&self) -> Option<&WaitEntry<V>> {
+        match &self.pending {
+            Some(entry) => Some(entry),
+            None        => self.shard.get(self.key()).map(SharedValue::get),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> Option<&mut WaitEntry<V>> {
+        self.pair_mut().map(|(_, entry)| entry)
+    }
+
+    pub(crate) fn pair_mut(&mut self) -> Option<(&K, &mut WaitEntry<V>)> {
+        let key = self.key.as_ref().unwrap();
+        match &mut self.pending {
+            Some(entry) => Some((key, entry)),
+            None        => self.shard.get_mut(key).map(|entry| (key, entry.get_mut())),
+        }
+    }
+
+    // Puts an entry in for the key, returning the one it replaces.
+    pub(crate) fn insert(&mut self, entry: WaitEntry<V>) -> Option<WaitEntry<V>> {
+        match self.get_mut() {
+            Some(old)   => Some(mem::replace(old, entry)),
+            None        => {
+                self.pending = Some(entry);
+                None
+            }
+        }
+    }
+
+    // Takes the key's entry out of the shard, keeping the lock.
+    pub(crate) fn take(&mut self) -> Option<WaitEntry<V>> {
+        match self.pending.take() {
+            Some(entry) => Some(entry),
+            None        => self.shard.remove(self.key.as_ref().unwrap()).map(|e| e.into_inner()),
+        }
+    }
+
+    pub(crate) fn remove_entry(mut self) -> Option<(K, WaitEntry<V>)> {
+        let key = self.key.take().unwrap();
+        match self.pending.take() {
+            Some(entry) => Some((key, entry)),
+            None        => self.shard.remove_entry(&key).map(|(k, e)| (k, e.into_inner())),
+        }
+    }
+
+    pub(crate) fn into_key(mut self) -> K {
+        debug_assert!(self.pending.is_none());
+        self.key.take().unwrap()
+    }
+}
+
+impl<'a, K, V, S> Drop for LockedKey<'a, K, V, S> {
+    fn drop(&mut self) {
+        if let (Some(key), Some(entry)) = (self.key.take(), self.pending.take()) {
+            (self.put)(&mut self.shard, key, entry);
+        }
+    }
+}
+
+// What a `RefMut` holds: either one of dashmap's guards, or a key of a shard locked by hand.
+pub(crate) enum GuardMut<'a, K, V, S> {
+    Map(one::RefMut<'a, K, WaitEntry<V>, S>),
+    Locked(LockedKey<'a, K, V, S>),
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> GuardMut<'a, K, V, S> {
+    pub(crate) fn key(&self) -> &K {
+        match self {
+            GuardMut::Map(inner)    => inner.key(),
+            GuardMut::Locked(inner) => inner.key(),
+        }
+    }
+
+    pub(crate) fn value(&self) -> &WaitEntry<V> {
+        match self {
+            GuardMut::Map(inner)    => inner.value(),
+            GuardMut::Locked(inner) => inner.get().unwrap(),
+        }
+    }
+
+    pub(crate) fn value_mut(&mut self) -> &mut WaitEntry<V> {
+        match self {
+            GuardMut::Map(inner)    => inner.value_mut(),
+            GuardMut::Locked(inner) => inner.get_mut().unwrap(),
+        }
+    }
+
+    pub(crate) fn pair_mut(&mut self) -> (&K, &mut WaitEntry<V>) {
+        match self {
+            GuardMut::Map(inner)    => inner.pair_mut(),
+            GuardMut::Locked(inner) => inner.pair_mut().unwrap(),
+        }
+    }
+}
+
+impl<'a, K, V, S> From<one::RefMut<'a, K, WaitEntry<V>, S>> for GuardMut<'a, K, V, S> {
+    fn from(inner: one::RefMut<'a, K, WaitEntry<V>, S>) -> Self {
+        GuardMut::Map(inner)
+    }
+}
+
+impl<'a, K, V, S> From<LockedKey<'a, K, V, S>> for GuardMut<'a, K, V, S> {
+    fn from(inner: LockedKey<'a, K, V, S>) -> Self {
+        GuardMut::Locked(inner)
+    }
+}
@@ -0,0 +1,88 @@
+use std::hash::{Hash, BuildHasher};
+
+use dashmap::lock::RwLockReadGuard;
+use rayon::prelude::*;
+
+use crate::WaitMap;
+use crate::WaitEntry::*;
+use crate::shard::{extract_matching, Shard};
+
+/// A read lock on every shard of a `WaitMap`, for iterating over it in parallel.
+///
+/// Created by [`WaitMap::par_iter`](crate::WaitMap::par_iter). The shards stay read locked
+/// until this is dropped, so the map cannot be written to in the meantime.
+pub struct ParIter<'a, K, V, S> {
+    shards: Vec<RwLockReadGuard<'a, Shard<K, V, S>>>,
+}
+
+impl<'a, K, V, S> ParIter<'a, K, V, S> where
+    K: Hash + Eq + Sync,
+    V: Sync,
+    S: BuildHasher + Clone + Sync,
+{
+    /// Iterates over the filled key-value pairs in parallel, skipping keys which are waiting
+    /// to be filled.
+    pub fn iter(&self) -> impl ParallelIterator<Item = (&K, &V)> + '_ {
+        self.shards.par_iter().flat_map_iter(|shard| {
+            shard.iter().filter_map(|(key, entry)| match entry.get() {
+                Filled(value, _) => Some((key, value)),
+                Waiting(_)       => None,
+            })
+        })
+    }
+}
+
+impl<K, V, S> WaitMap<K, V, S> where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher + Clone + Send + Sync,
+{
+    /// Read locks the whole map, to iterate over its filled entries in parallel.
+    ///
+    /// Requires the `rayon` feature.
+    /// ```
+    /// # extern crate rayon;
+    /// # extern crate waitmap;
+    /// # use waitmap::WaitMap;
+    /// use rayon::prelude::*;
+    ///
+    /// let map: WaitMap<i32, i32> = WaitMap::new();
+    /// map.insert_sorted((0..100).map(|i| (i, i)));
+    ///
+    /// let sum: i32 = map.par_iter().iter().map(|(_, value)| *value).sum();
+    /// assert_eq!(sum, 4950);
+    /// ```
+    pub fn par_iter(&self) -> ParIter<'_, K, V, S> {
+        ParIter { shards: self.map.shards().iter().map(|shard| shard.read()).collect() }
+    }
+
+    /// Retains only the filled entries for which `f` returns `true`, visiting the shards in
+    /// parallel.
+    ///
+    /// Keys which are waiting to be filled are left alone. Requires the `rayon` feature.
+    /// ```
+    /// # extern crate waitmap;
+    /// # use waitmap::WaitMap;
+    /// let map: WaitMap<i32, i32> = WaitMap::new();
+    /// map.insert_sorted((0..100).map(|i| (i, i)));
+    ///
+    /// map.par_retain(|_, value| *value % 10 == 0);
+    /// assert!(map.get(&10).is_some());
+    /// assert!(map.get(&11).is_none());
+    /// ```
+    pub fn par_retain(&self, f: impl Fn(&K, &mut V) -> bool + Send + Sync) {
+        self.map.shards().par_iter().for_each(|shard| {
+            let removed = extract_matching(&mut shard.write(), |key, entry| match entry {
+                Filled(value, _) => !f(key, value),
+                Waiting(_)       => false,
+            });
+
+            for (key, entry) in removed {
+                if let Filled(value, _) = entry {
+                    self.count_removal(&key);
+                    self.hooks.removed(&key, &value);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,70 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{Hash, BuildHasher};
+
+use dashmap::ReadOnlyView;
+
+use crate::WaitEntry;
+use crate::WaitEntry::*;
+
+/// A read-only view of a `WaitMap`, created by
+/// [`WaitMap::into_read_only`](crate::WaitMap::into_read_only).
+///
+/// The view only contains filled entries, and it is impossible to wait on it. Because it
+/// cannot be modified, it hands out plain references rather than guards.
+pub struct ReadOnlyWaitMap<K, V, S = RandomState> {
+    view: ReadOnlyView<K, WaitEntry<V>, S>,
+}
+
+impl<K, V, S> ReadOnlyWaitMap<K, V, S> {
+    pub(crate) fn new(view: ReadOnlyView<K, WaitEntry<V>, S>) -> ReadOnlyWaitMap<K, V, S> {
+        ReadOnlyWaitMap { view }
+    }
+}
+
+impl<'a, K: 'a + Hash + Eq, V: 'a, S: BuildHasher + Clone> ReadOnlyWaitMap<K, V, S> {
+    pub fn len(&self) -> usize {
+        self.view.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.view.is_empty()
+    }
+
+    pub fn contains_key<Q: ?Sized + Hash + Eq>(&'a self, key: &Q) -> bool
+        where K: Borrow<Q>
+    {
+        self.view.contains_key(key)
+    }
+
+    pub fn get<Q: ?Sized + Hash + Eq>(&'a self, key: &Q) -> Option<&'a V>
+        where K: Borrow<Q>
+    {
+        self.view.get(key).map(filled)
+    }
+
+    pub fn get_key_value<Q: ?Sized + Hash + Eq>(&'a self, key: &Q) -> Option<(&'a K, &'a V)>
+        where K: Borrow<Q>
+    {
+        self.view.get_key_value(key).map(|(key, entry)| (key, filled(entry)))
+    }
+
+    pub fn iter(&'a self) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        self.view.iter().map(|(key, entry)| (key, filled(entry)))
+    }
+
+    pub fn keys(&'a self) -> impl Iterator<Item = &'a K> + 'a {
+        self.view.keys()
+    }
+
+    pub fn values(&'a self) -> impl Iterator<Item = &'a V> + 'a {
+        self.view.values().map(filled)
+    }
+}
+
+fn filled<V>(entry: &WaitEntry<V>) -> &V {
+    match entry {
+        Filled(value, _) => value,
+        _                => panic!()
+    }
+}
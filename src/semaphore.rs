@@ -0,0 +1,60 @@
+use std::mem;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+// Limits how many references resolved by waits can be held at once.
+pub(crate) struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    available: usize,
+    waiting: Vec<Waker>,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Semaphore {
+        Semaphore { state: Mutex::new(State { available: permits, waiting: Vec::new() }) }
+    }
+
+    // Takes a permit if one is available, registering the task to be woken when one is
+    // released if not.
+    pub(crate) fn poll_acquire(&self, ctx: &mut Context<'_>) -> Poll<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.available > 0 {
+            state.available -= 1;
+            Poll::Ready(Permit { semaphore: self })
+        } else {
+            if !state.waiting.iter().any(|waker| waker.will_wake(ctx.waker())) {
+                state.waiting.push(ctx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.available += 1;
+        // NB: every waiting task is woken, not just one, because a task may have stopped
+        // waiting without telling the semaphore; waking only it would strand the permit.
+        let waiting = mem::take(&mut state.waiting);
+        drop(state);
+        waiting.into_iter().for_each(Waker::wake);
+    }
+}
+
+// A guard which holds on to a permit until it is dropped.
+pub(crate) trait HoldPermit<'a> {
+    fn hold(&mut self, permit: Permit<'a>);
+}
+
+// A permit taken from a `Semaphore`, which is given back when it is dropped.
+pub(crate) struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
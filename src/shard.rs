@@ -0,0 +1,150 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{Hash, BuildHasher};
+use std::vec;
+
+use dashmap::SharedValue;
+
+use crate::{WaitMap, WaitEntry};
+use crate::WaitEntry::*;
+use crate::waker_set::WakerSet;
+
+pub(crate) type Shard<K, V, S> = HashMap<K, SharedValue<WaitEntry<V>>, S>;
+
+/// A write locked view of one shard of a `WaitMap`.
+///
+/// Created by [`WaitMap::with_shard`](crate::WaitMap::with_shard). Every operation on the view
+/// happens under the same lock, and only keys which belong to the shard may be used with it;
+/// passing any other key panics.
+pub struct ShardView<'v, K, V, S> {
+    map: &'v WaitMap<K, V, S>,
+    index: usize,
+    shard: &'v mut Shard<K, V, S>,
+    woken: &'v mut Vec<WakerSet>,
+}
+
+impl<'v, K: Hash + Eq, V, S: BuildHasher + Clone> ShardView<'v, K, V, S> {
+    pub(crate) fn new(
+        map: &'v WaitMap<K, V, S>,
+        index: usize,
+        shard: &'v mut Shard<K, V, S>,
+        woken: &'v mut Vec<WakerSet>,
+    ) -> Self {
+        ShardView { map, index, shard, woken }
+    }
+
+    /// Returns the index of the shard this view locks.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>
+    {
+        self.check(key);
+        match self.shard.get(key)?.get() {
+            Filled(value, _) => Some(value),
+            Waiting(_)       => None,
+        }
+    }
+
+    pub fn get_mut<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<&mut V>
+        where K: Borrow<Q>
+    {
+        self.check(key);
+        match self.shard.get_mut(key)?.get_mut() {
+            Filled(value, _) => Some(value),
+            Waiting(_)       => None,
+        }
+    }
+
+    /// Inserts a key-value pair into the shard, returning the old value if the key was filled.
+    ///
+    /// Any pending `wait` calls for this key are woken up once the shard's lock is released,
+    /// but the `on_insert` callback runs under the lock, so it must not access the map.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.check(&key);
+        self.map.hooks.inserted(&key, &value);
+        self.map.listeners.filled(&key, &value);
+        let old = self.shard.insert(key, SharedValue::new(Filled(value, None)));
+        match old.map(SharedValue::into_inner) {
+            Some(Filled(value, held_back)) => {
+                self.woken.extend(held_back.map(|wakers| *wakers));
+                Some(value)
+            }
+            Some(Waiting(wakers))   => {
+                self.map.count_fills(1);
+                self.woken.push(wakers);
+                None
+            }
+            None                    => {
+                self.map.count_fills(1);
+                None
+            }
+        }
+    }
+
+    /// Removes a key from the shard, returning its value if it was filled.
+    ///
+    /// As with [`WaitMap::remove`](crate::WaitMap::remove), a key which was waiting to be
+    /// filled is removed anyway, and its pending `wait` calls evaluate to `None`. The
+    /// `on_remove` and `on_cancel` callbacks run under the shard's lock.
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>
+    {
+        self.check(key);
+        match self.shard.remove_entry(key).map(|(key, entry)| (key, entry.into_inner()))? {
+            (key, Filled(value, _))     => {
+                self.map.count_removal(&key);
+                self.map.hooks.removed(&key, &value);
+                Some(value)
+            }
+            (key, Waiting(mut wakers))  => {
+                // NB: the wakers are moved out of the set first, so that waking them once the
+                // lock is released leaves its depth at zero, as for any other removal.
+                self.woken.push(wakers.cancel());
+                self.map.hooks.cancelled(&key);
+                None
+            }
+        }
+    }
+
+    fn check<Q: ?Sized + Hash + Eq>(&self, key: &Q)
+        where K: Borrow<Q>
+    {
+        assert_eq!(self.map.shard_index(key), self.index, "key does not belong to this shard");
+    }
+}
+
+// Removes the entries of a shard which match a predicate, returning them.
+//
+// NB: this drains the shard and puts back the entries which don't match, since
+// `HashMap::extract_if` needs a newer Rust than this crate supports. The entries not yet
+// looked at are put back even if the predicate panics.
+pub(crate) fn extract_matching<K: Hash + Eq, V, S: BuildHasher>(
+    shard: &mut Shard<K, V, S>,
+    mut pred: impl FnMut(&K, &mut WaitEntry<V>) -> bool,
+) -> Vec<(K, WaitEntry<V>)> {
+    struct PutBack<'s, K: Hash + Eq, V, S: BuildHasher> {
+        shard: &'s mut Shard<K, V, S>,
+        entries: vec::IntoIter<(K, SharedValue<WaitEntry<V>>)>,
+    }
+
+    impl<'s, K: Hash + Eq, V, S: BuildHasher> Drop for PutBack<'s, K, V, S> {
+        fn drop(&mut self) {
+            self.shard.extend(&mut self.entries);
+        }
+    }
+
+    let entries: Vec<_> = shard.drain().collect();
+    let mut rest = PutBack { shard, entries: entries.into_iter() };
+    let mut removed = vec![];
+    for (key, mut entry) in rest.entries.by_ref() {
+        if pred(&key, entry.get_mut()) {
+            removed.push((key, entry.into_inner()));
+        } else {
+            rest.shard.insert(key, entry);
+        }
+    }
+    removed
+}
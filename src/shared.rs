@@ -0,0 +1,86 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{Hash, BuildHasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::WaitMap;
+
+/// A cheaply cloneable handle to a shared `WaitMap`.
+///
+/// Cloning the handle only clones the `Arc` inside it, so it can be handed out to any number of
+/// tasks. All of the methods of `WaitMap` are available through `Deref`.
+/// ```
+/// # extern crate async_std;
+/// # extern crate waitmap;
+/// # use async_std::{main, task};
+/// # use waitmap::SharedWaitMap;
+/// # #[async_std::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let map: SharedWaitMap<String, i32> = SharedWaitMap::new();
+/// let map1 = map.clone();
+///
+/// let handle = task::spawn(async move {
+///     assert_eq!(map1.wait("Nestor Makhno").await.unwrap().value(), &0);
+/// });
+///
+/// map.insert("Nestor Makhno".to_string(), 0);
+/// handle.await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedWaitMap<K, V, S = RandomState> {
+    inner: Arc<WaitMap<K, V, S>>,
+}
+
+impl<K: Hash + Eq, V> SharedWaitMap<K, V> {
+    /// Make a new `SharedWaitMap` using the default hasher.
+    pub fn new() -> SharedWaitMap<K, V> {
+        SharedWaitMap::from(WaitMap::new())
+    }
+}
+
+impl<K: Hash + Eq, V> Default for SharedWaitMap<K, V> {
+    fn default() -> SharedWaitMap<K, V> {
+        SharedWaitMap::new()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> SharedWaitMap<K, V, S> {
+    /// Make a new `SharedWaitMap` using a custom hasher.
+    pub fn with_hasher(hasher: S) -> SharedWaitMap<K, V, S> {
+        SharedWaitMap::from(WaitMap::with_hasher(hasher))
+    }
+}
+
+impl<K, V, S> SharedWaitMap<K, V, S> {
+    /// Returns the `Arc` this handle wraps.
+    pub fn into_arc(self) -> Arc<WaitMap<K, V, S>> {
+        self.inner
+    }
+}
+
+impl<K, V, S> Clone for SharedWaitMap<K, V, S> {
+    fn clone(&self) -> SharedWaitMap<K, V, S> {
+        SharedWaitMap { inner: self.inner.clone() }
+    }
+}
+
+impl<K, V, S> Deref for SharedWaitMap<K, V, S> {
+    type Target = WaitMap<K, V, S>;
+
+    fn deref(&self) -> &WaitMap<K, V, S> {
+        &self.inner
+    }
+}
+
+impl<K, V, S> From<WaitMap<K, V, S>> for SharedWaitMap<K, V, S> {
+    fn from(map: WaitMap<K, V, S>) -> SharedWaitMap<K, V, S> {
+        SharedWaitMap { inner: Arc::new(map) }
+    }
+}
+
+impl<K, V, S> From<Arc<WaitMap<K, V, S>>> for SharedWaitMap<K, V, S> {
+    fn from(inner: Arc<WaitMap<K, V, S>>) -> SharedWaitMap<K, V, S> {
+        SharedWaitMap { inner }
+    }
+}
@@ -0,0 +1,39 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_timer::Delay;
+
+/// Races a future against another, evaluating to `None` if the other completes first.
+pub(crate) struct Until<F, C> {
+    future: F,
+    cancel: C,
+}
+
+/// Races a future against a timer, evaluating to `None` if the timer fires first.
+pub(crate) type Timeout<F> = Until<F, Delay>;
+
+pub(crate) fn timeout<F: Future + Unpin>(future: F, dur: Duration) -> Timeout<F> {
+    Until::new(future, Delay::new(dur))
+}
+
+impl<F: Future + Unpin, C: Future + Unpin> Until<F, C> {
+    pub(crate) fn new(future: F, cancel: C) -> Until<F, C> {
+        Until { future, cancel }
+    }
+}
+
+impl<F: Future + Unpin, C: Future + Unpin> Future for Until<F, C> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = Pin::new(&mut self.future).poll(ctx) {
+            return Poll::Ready(Some(output));
+        }
+        match Pin::new(&mut self.cancel).poll(ctx) {
+            Poll::Ready(_)      => Poll::Ready(None),
+            Poll::Pending       => Poll::Pending,
+        }
+    }
+}
@@ -1,23 +1,44 @@
 use std::borrow::Borrow;
 use std::future::Future;
 use std::hash::{Hash, BuildHasher};
+use std::mem;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
 
-use dashmap::DashMap;
+use futures_core::future::FusedFuture;
 
-use crate::WaitEntry;
+use crate::WaitMap;
+use crate::handle::{CancelState, CancelToken, WaitHandle};
+use crate::hooks::WaitOutcome;
+use crate::listen::Listener;
+use crate::semaphore::{HoldPermit, Permit};
+use crate::timeout::Timeout;
+use crate::waker_set::WakerSet;
 use crate::WaitEntry::*;
 use crate::{Ref, RefMut};
 
+/// A future which waits for a key to be filled.
+///
+/// Created by [`WaitMap::reserve_wait`](crate::WaitMap::reserve_wait),
+/// [`WaitMap::wait_with_handle`](crate::WaitMap::wait_with_handle) and
+/// [`WaitMap::wait_with_token`](crate::WaitMap::wait_with_token).
 pub struct Wait<'a, 'b, K, V, S, Q> where
     K: Hash + Eq + Borrow<Q>,
     S: BuildHasher + Clone,
     Q: ?Sized + Hash + Eq,
 {
-    map: &'a DashMap<K, WaitEntry<V>, S>,
+    map: &'a WaitMap<K, V, S>,
     key: &'b Q,
     idx: usize,
+    parked: Option<Parked>,
+    done: bool,
+    // only allocated once the wait can be cancelled, see `cancel_handle`
+    cancel: OnceLock<Arc<CancelState>>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
 }
 
 impl<'a, 'b, K, V, S, Q> Wait<'a, 'b, K, V, S, Q> where
@@ -25,8 +46,58 @@ impl<'a, 'b, K, V, S, Q> Wait<'a, 'b, K, V, S, Q> where
     S: BuildHasher + Clone,
     Q: ?Sized + Hash + Eq,
 {
-    pub(crate) fn new(map: &'a DashMap<K, WaitEntry<V>, S>, key: &'b Q) -> Self {
-        Wait { map, key, idx: std::usize::MAX }
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: &'b Q, reservation: Option<Arc<()>>)
+        -> Self
+    {
+        Wait::with_cancel(map, key, reservation, OnceLock::new())
+    }
+
+    pub(crate) fn with_handle(
+        map: &'a WaitMap<K, V, S>,
+        key: &'b Q,
+        reservation: Option<Arc<()>>,
+    ) -> (Self, WaitHandle) {
+        let (handle, state) = WaitHandle::new();
+        (Wait::with_cancel(map, key, reservation, OnceLock::from(state)), handle)
+    }
+
+    pub(crate) fn with_token(
+        map: &'a WaitMap<K, V, S>,
+        key: &'b Q,
+        reservation: Option<Arc<()>>,
+        token: &CancelToken,
+    ) -> Self {
+        let (_, state) = WaitHandle::new();
+        token.attach(&state);
+        Wait::with_cancel(map, key, reservation, OnceLock::from(state))
+    }
+
+    fn with_cancel(
+        map: &'a WaitMap<K, V, S>,
+        key: &'b Q,
+        reservation: Option<Arc<()>>,
+        cancel: OnceLock<Arc<CancelState>>,
+    ) -> Self {
+        Wait { map, key, idx: usize::MAX, parked: None, done: false, cancel, reservation }
+    }
+
+    /// Returns a handle which cancels this wait, so that it evaluates to `None`.
+    ///
+    /// Unlike [`WaitMap::wait_with_handle`](crate::WaitMap::wait_with_handle), the handle does
+    /// not have to be asked for up front: the state it shares with the future is allocated the
+    /// first time this is called, and later calls return handles to the same state. If the
+    /// future was created with a handle or a token, the returned handle cancels it as well.
+    pub fn cancel_handle(&self) -> WaitHandle {
+        let state = self.cancel.get_or_init(|| {
+            let (_, state) = WaitHandle::new();
+            // a wait which has already been polled is only woken through its key, so the waker
+            // it registered there is the one to wake on cancellation
+            if let Some(waker) = self.map.registered_waker(self.key, self.idx) {
+                state.register(&waker);
+            }
+            state
+        });
+        WaitHandle::for_state(state)
     }
 }
 
@@ -38,20 +109,34 @@ impl<'a, 'b, K, V, S, Q> Future for Wait<'a, 'b, K, V, S, Q> where
     type Output = Option<Ref<'a, K, V, S>>;
 
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.map.get_mut(self.key) {
-            Some(mut entry) => match entry.value_mut() {
-                Waiting(wakers)  => {
-                    wakers.replace(ctx.waker().clone(), &mut self.idx);
-                    Poll::Pending
-                }
-                Filled(_)        => {
-                    let inner = entry.downgrade();
-                    self.idx = std::usize::MAX;
-                    Poll::Ready(Some(Ref { inner }))
-                }
+        assert!(!self.done, "`Wait` polled after completion");
+        let this = &mut *self;
+        let (map, key) = (this.map, this.key);
+        let poll = if this.cancel.get().is_some_and(|cancel| cancel.register(ctx.waker())) {
+            map.remove_waker(key, this.idx);
+            this.idx = usize::MAX;
+            Poll::Ready(None)
+        } else {
+            match map.poll_ref(key, &mut this.idx, ctx, Some(&mut this.parked)) {
+                Poll::Ready(Some(entry))    => map.poll_permit(entry, ctx),
+                poll                        => poll,
             }
-            None        => Poll::Ready(None),
-        }
+        };
+        // the waker is registered on the key by now, if it is still waiting
+        this.reservation = None;
+        let poll = map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for Wait<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
     }
 }
 
@@ -61,12 +146,7 @@ impl<'a, 'b, K, V, S, Q> Drop for Wait<'a, 'b, K, V, S, Q> where
     Q: ?Sized + Hash + Eq,
 {
     fn drop(&mut self) {
-        if self.idx == std::usize::MAX { return; }
-        if let Some(mut entry) = self.map.get_mut(self.key) {
-            if let Waiting(wakers) = entry.value_mut() {
-                wakers.remove(self.idx);
-            }
-        }
+        self.map.drop_waker(self.key, self.idx);
     }
 }
 
@@ -75,9 +155,13 @@ pub struct WaitMut<'a, 'b, K, V, S, Q> where
     S: BuildHasher + Clone,
     Q: ?Sized + Hash + Eq,
 {
-    map: &'a DashMap<K, WaitEntry<V>, S>,
+    map: &'a WaitMap<K, V, S>,
     key: &'b Q,
     idx: usize,
+    parked: Option<Parked>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
 }
 
 impl<'a, 'b, K, V, S, Q> WaitMut<'a, 'b, K, V, S, Q> where
@@ -85,8 +169,10 @@ impl<'a, 'b, K, V, S, Q> WaitMut<'a, 'b, K, V, S, Q> where
     S: BuildHasher + Clone,
     Q: ?Sized + Hash + Eq,
 {
-    pub(crate) fn new(map: &'a DashMap<K, WaitEntry<V>, S>, key: &'b Q) -> Self {
-        WaitMut { map, key, idx: std::usize::MAX }
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: &'b Q, reservation: Option<Arc<()>>)
+        -> Self
+    {
+        WaitMut { map, key, idx: usize::MAX, parked: None, reservation, done: false }
     }
 }
 
@@ -98,19 +184,27 @@ impl<'a, 'b, K, V, S, Q> Future for WaitMut<'a, 'b, K, V, S, Q> where
     type Output = Option<RefMut<'a, K, V, S>>;
 
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.map.get_mut(self.key) {
-            Some(mut entry) => match entry.value_mut() {
-                Waiting(wakers)  => {
-                    wakers.replace(ctx.waker().clone(), &mut self.idx);
-                    Poll::Pending
-                }
-                Filled(_)        => {
-                    self.idx = std::usize::MAX;
-                    Poll::Ready(Some(RefMut { inner: entry }))
-                }
-            }
-            None        => Poll::Ready(None),
-        }
+        assert!(!self.done, "`WaitMut` polled after completion");
+        let this = &mut *self;
+        let (map, key) = (this.map, this.key);
+        let poll = match map.poll_ref_mut(key, &mut this.idx, ctx, Some(&mut this.parked)) {
+            Poll::Ready(Some(entry))    => map.poll_permit(entry, ctx),
+            poll                        => poll,
+        };
+        this.reservation = None;
+        let poll = map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for WaitMut<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
     }
 }
 
@@ -120,11 +214,1286 @@ impl<'a, 'b, K, V, S, Q> Drop for WaitMut<'a, 'b, K, V, S, Q> where
     Q: ?Sized + Hash + Eq,
 {
     fn drop(&mut self) {
-        if self.idx == std::usize::MAX { return; }
-        if let Some(mut entry) = self.map.get_mut(self.key) {
-            if let Waiting(wakers) = entry.value_mut() {
-                wakers.remove(self.idx);
+        self.map.drop_waker(self.key, self.idx);
+    }
+}
+
+/// A future which waits for a key to be filled, then removes it from the map.
+///
+/// Created by [`WaitMap::wait_take`](crate::WaitMap::wait_take).
+///
+/// Checking that the key is filled and removing it happen under the same shard lock, so a
+/// `WaitTake` which observes the value always receives it. It only evaluates to `None` if the
+/// key was missing when it was polled: because the wait was cancelled, or because the key was
+/// removed or taken by another task after it was filled. When several tasks are waiting to
+/// take the same key, they are all woken when it is filled, and the first one to be polled
+/// takes the value.
+pub struct WaitTake<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: &'b Q,
+    idx: usize,
+    parked: Option<Parked>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<'a, 'b, K, V, S, Q> WaitTake<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: &'b Q, reservation: Option<Arc<()>>)
+        -> Self
+    {
+        WaitTake { map, key, idx: usize::MAX, parked: None, reservation, done: false }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Future for WaitTake<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    type Output = Option<(K, V)>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitTake` polled after completion");
+        let this = &mut *self;
+        let poll = this.map.poll_take(this.key, &mut this.idx, ctx, &mut this.parked, None)
+            .map(RemoveOutcome::removed);
+        this.reservation = None;
+        let poll = this.map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for WaitTake<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Drop for WaitTake<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn drop(&mut self) {
+        self.map.drop_waker(self.key, self.idx);
+    }
+}
+
+/// How a [`WaitTakeDetailed`] future was resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoveOutcome<K, V> {
+    /// The key was filled, and this task removed it from the map.
+    Removed((K, V)),
+    /// The key was filled while this task was waiting on it, but another task removed the
+    /// value before this one was polled again.
+    Lost,
+    /// The key was missing without having been filled: its wait was cancelled, or it was
+    /// removed before this task started waiting on it.
+    KeyGone,
+}
+
+impl<K, V> RemoveOutcome<K, V> {
+    /// Returns the removed key-value pair, if there is one.
+    pub fn removed(self) -> Option<(K, V)> {
+        match self {
+            RemoveOutcome::Removed(pair)    => Some(pair),
+            _                               => None,
+        }
+    }
+}
+
+/// A future which waits for a key to be filled, then removes it from the map, reporting why
+/// it did not get the value if it did not.
+///
+/// Created by [`WaitMap::wait_take_detailed`](crate::WaitMap::wait_take_detailed). It behaves
+/// like [`WaitTake`], but evaluates to a [`RemoveOutcome`].
+pub struct WaitTakeDetailed<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: &'b Q,
+    idx: usize,
+    parked: Option<Parked>,
+    // the depth of the waiters this future registered with, which is set once they are filled
+    filled: Option<Arc<AtomicUsize>>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<'a, 'b, K, V, S, Q> WaitTakeDetailed<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: &'b Q, reservation: Option<Arc<()>>)
+        -> Self
+    {
+        WaitTakeDetailed {
+            map, key, idx: usize::MAX, parked: None, filled: None, reservation, done: false,
+        }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Future for WaitTakeDetailed<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    type Output = RemoveOutcome<K, V>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitTakeDetailed` polled after completion");
+        let this = &mut *self;
+        let filled = Some(&mut this.filled);
+        let poll = this.map.poll_take(this.key, &mut this.idx, ctx, &mut this.parked, filled);
+        this.reservation = None;
+        if let Poll::Ready(outcome) = &poll {
+            let removed = match outcome {
+                RemoveOutcome::Removed(pair)    => Some(pair),
+                _                               => None,
+            };
+            let _ = this.map.time_wait(&mut this.parked, Poll::Ready(removed));
+        }
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for WaitTakeDetailed<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Drop for WaitTakeDetailed<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn drop(&mut self) {
+        self.map.drop_waker(self.key, self.idx);
+    }
+}
+
+/// A future which waits for a key to be filled for a limited time, then fills it with the
+/// default value.
+///
+/// Created by
+/// [`WaitMap::wait_mut_or_insert_default`](crate::WaitMap::wait_mut_or_insert_default).
+pub struct WaitMutOrDefault<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: &'b Q,
+    wait: Option<Timeout<WaitMut<'a, 'b, K, V, S, Q>>>,
+    done: bool,
+}
+
+impl<'a, 'b, K, V, S, Q> WaitMutOrDefault<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    pub(crate) fn new(
+        map: &'a WaitMap<K, V, S>,
+        key: &'b Q,
+        wait: Timeout<WaitMut<'a, 'b, K, V, S, Q>>,
+    ) -> Self {
+        WaitMutOrDefault { map, key, wait: Some(wait), done: false }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Future for WaitMutOrDefault<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q> + From<&'b Q>,
+    V: Default,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    type Output = RefMut<'a, K, V, S>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "polled after completion");
+        if let Some(wait) = self.wait.as_mut() {
+            let output = match Pin::new(wait).poll(ctx) {
+                Poll::Ready(output) => output,
+                Poll::Pending       => return Poll::Pending,
+            };
+
+            // drop the wait first, so that it deregisters its waker before we lock the shard
+            self.wait = None;
+            if let Some(Some(entry)) = output {
+                self.done = true;
+                return Poll::Ready(entry);
             }
         }
+
+        // NB: the permit is taken before the key is filled, so that a fill is never held back.
+        let permit = match self.map.poll_in_flight(ctx) {
+            Poll::Ready(permit) => permit,
+            Poll::Pending       => return Poll::Pending,
+        };
+        let mut entry = self.map.fill_ref(K::from(self.key), V::default);
+        if let Some(permit) = permit { entry.hold(permit); }
+        self.done = true;
+        Poll::Ready(entry)
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for WaitMutOrDefault<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q> + From<&'b Q>,
+    V: Default,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+/// A future which waits for an owned key to be filled.
+pub struct WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: K,
+    idx: usize,
+    parked: Option<Parked>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<'a, K, V, S> WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: K, reservation: Option<Arc<()>>) -> Self {
+        WaitEq { map, key, idx: usize::MAX, parked: None, reservation, done: false }
+    }
+}
+
+impl<'a, K, V, S> Unpin for WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<'a, K, V, S> Future for WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    type Output = Option<Ref<'a, K, V, S>>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitEq` polled after completion");
+        let this = &mut *self;
+        let poll = match this.map.poll_ref(&this.key, &mut this.idx, ctx, Some(&mut this.parked)) {
+            Poll::Ready(Some(entry))    => this.map.poll_permit(entry, ctx),
+            poll                        => poll,
+        };
+        this.reservation = None;
+        let poll = this.map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, K, V, S> FusedFuture for WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, K, V, S> Drop for WaitEq<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        self.map.drop_waker(&self.key, self.idx);
+    }
+}
+
+/// A future which waits for a key to be filled, without keeping the map alive.
+///
+/// Created by [`WaitMap::wait_weak`](crate::WaitMap::wait_weak).
+pub struct WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: Weak<WaitMap<K, V, S>>,
+    key: K,
+    idx: usize,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<K, V, S> WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(map: Weak<WaitMap<K, V, S>>, key: K, reservation: Option<Arc<()>>)
+        -> Self
+    {
+        WaitWeak { map, key, idx: usize::MAX, reservation, done: false }
+    }
+}
+
+impl<K, V, S> Unpin for WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<K, V, S> Future for WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    type Output = Option<V>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitWeak` polled after completion");
+        let this = &mut *self;
+        let poll = match this.map.upgrade() {
+            Some(map)   => map.poll_ref(&this.key, &mut this.idx, ctx, None)
+                .map(|entry| entry.map(|entry| entry.value().clone())),
+            None        => Poll::Ready(None),
+        };
+        this.reservation = None;
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<K, V, S> FusedFuture for WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<K, V, S> Drop for WaitWeak<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(map) = self.map.upgrade() {
+            map.drop_waker(&self.key, self.idx);
+        }
+    }
+}
+
+/// A future which waits for a key to be filled, keeping the map alive.
+///
+/// Created by [`WaitMapExt::spawn_wait`](crate::WaitMapExt::spawn_wait). The future owns a
+/// clone of the map's `Arc` and of the key, so it can be spawned onto another task.
+pub struct WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: Arc<WaitMap<K, V, S>>,
+    key: K,
+    idx: usize,
+    parked: Option<Parked>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<K, V, S> WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(map: Arc<WaitMap<K, V, S>>, key: K, reservation: Option<Arc<()>>) -> Self {
+        WaitOwned { map, key, idx: usize::MAX, parked: None, reservation, done: false }
+    }
+}
+
+impl<K, V, S> Unpin for WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<K, V, S> Future for WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    type Output = Option<V>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitOwned` polled after completion");
+        let this = &mut *self;
+        let poll = this.map.poll_ref(&this.key, &mut this.idx, ctx, Some(&mut this.parked))
+            .map(|entry| entry.map(|entry| entry.value().clone()));
+        this.reservation = None;
+        let poll = this.map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<K, V, S> FusedFuture for WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<K, V, S> Drop for WaitOwned<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        self.map.drop_waker(&self.key, self.idx);
+    }
+}
+
+/// A future which waits for a key to be filled and then removes it, keeping the map alive.
+///
+/// Created by [`WaitMap::wait_owned_take`](crate::WaitMap::wait_owned_take). It behaves like
+/// [`WaitTake`], but owns a clone of the map's `Arc` and of the key, so it can be spawned onto
+/// another task.
+pub struct WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: Arc<WaitMap<K, V, S>>,
+    key: K,
+    idx: usize,
+    parked: Option<Parked>,
+    // keeps the key's placeholder in the map until the first poll registers a waker on it
+    reservation: Option<Arc<()>>,
+    done: bool,
+}
+
+impl<K, V, S> WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(map: Arc<WaitMap<K, V, S>>, key: K, reservation: Option<Arc<()>>) -> Self {
+        WaitOwnedTake { map, key, idx: usize::MAX, parked: None, reservation, done: false }
+    }
+}
+
+impl<K, V, S> Unpin for WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<K, V, S> Future for WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    type Output = Option<(K, V)>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitOwnedTake` polled after completion");
+        let this = &mut *self;
+        let poll = this.map.poll_take(&this.key, &mut this.idx, ctx, &mut this.parked, None)
+            .map(RemoveOutcome::removed);
+        this.reservation = None;
+        let poll = this.map.time_wait(&mut this.parked, poll);
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<K, V, S> FusedFuture for WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<K, V, S> Drop for WaitOwnedTake<K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        self.map.drop_waker(&self.key, self.idx);
+    }
+}
+
+/// A future which waits for any key matching a predicate to be filled.
+///
+/// Created by [`WaitMap::wait_matching`](crate::WaitMap::wait_matching) and
+/// [`WaitMap::wait_any_insert`](crate::WaitMap::wait_any_insert).
+pub struct WaitMatching<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: &'a WaitMap<K, V, S>,
+    pred: Arc<dyn Fn(&K) -> bool + Send + Sync>,
+    found: Arc<Mutex<Found<K, V>>>,
+    // whether keys which are already filled when the future is first polled count
+    existing: bool,
+    id: Option<usize>,
+    done: bool,
+}
+
+// What the listener of a `WaitMatching` has seen since it was registered.
+struct Found<K, V> {
+    pair: Option<(K, V)>,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+impl<'a, K, V, S> WaitMatching<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(
+        map: &'a WaitMap<K, V, S>,
+        pred: Arc<dyn Fn(&K) -> bool + Send + Sync>,
+        existing: bool,
+    ) -> Self {
+        let found = Arc::new(Mutex::new(Found { pair: None, cancelled: false, waker: None }));
+        WaitMatching { map, pred, found, existing, id: None, done: false }
+    }
+}
+
+impl<'a, K, V, S> WaitMatching<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    fn listener(&self) -> Listener<K, V> {
+        let pred = self.pred.clone();
+        let found = self.found.clone();
+        Box::new(move |filled| {
+            let mut found = found.lock().unwrap();
+            match filled {
+                Some((key, value)) if pred(key) => {
+                    found.pair = Some((key.clone(), value.clone()));
+                }
+                Some(_)                         => return false,
+                None                            => found.cancelled = true,
+            }
+            if let Some(waker) = found.waker.take() {
+                waker.wake();
+            }
+            true
+        })
+    }
+
+    // Starts listening for keys being filled before the future is first polled.
+    pub(crate) fn listen(&mut self) -> usize {
+        match self.id {
+            Some(id)    => id,
+            None        => *self.id.insert(self.map.listeners.add(self.listener())),
+        }
+    }
+
+    fn poll_found(&mut self, ctx: &mut Context<'_>) -> Poll<Option<(K, V)>> {
+        {
+            let mut found = self.found.lock().unwrap();
+            if let Some(pair) = found.pair.take() {
+                return Poll::Ready(Some(pair));
+            }
+            if found.cancelled {
+                return Poll::Ready(None);
+            }
+            found.waker = Some(ctx.waker().clone());
+        }
+
+        if self.id.is_none() {
+            if self.map.is_closed() {
+                return Poll::Ready(None);
+            }
+
+            // NB: the listener is registered before looking through the map, so a key filled
+            // while the map is being looked through is either found or passed to the listener.
+            let id = self.listen();
+            if !self.existing { return Poll::Pending; }
+            if let Some(pair) = self.map.find_filled(&*self.pred) {
+                self.map.listeners.remove(id);
+                return Poll::Ready(Some(pair));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<'a, K, V, S> Unpin for WaitMatching<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<'a, K, V, S> Future for WaitMatching<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    type Output = Option<(K, V)>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitMatching` polled after completion");
+        let poll = self.poll_found(ctx);
+        self.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, K, V, S> FusedFuture for WaitMatching<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, K, V, S> Drop for WaitMatching<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.map.listeners.remove(id);
+        }
+    }
+}
+
+/// A future which waits for a filled key to be removed from the map.
+///
+/// Created by [`WaitMap::wait_removed`](crate::WaitMap::wait_removed).
+pub struct WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: K,
+    seen: Arc<Mutex<Seen>>,
+    id: Option<usize>,
+    done: bool,
+}
+
+// What the listener of a `WaitRemoved` has seen since it was registered.
+struct Seen {
+    removed: bool,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+impl<'a, K, V, S> WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: K) -> Self {
+        let seen = Arc::new(Mutex::new(Seen { removed: false, cancelled: false, waker: None }));
+        WaitRemoved { map, key, seen, id: None, done: false }
+    }
+
+    fn listener(key: K, seen: Arc<Mutex<Seen>>) -> Listener<K, ()> {
+        Box::new(move |removed| {
+            let mut seen = seen.lock().unwrap();
+            match removed {
+                Some((removed, _)) if *removed == key   => seen.removed = true,
+                Some(_)                                 => return false,
+                None                                    => seen.cancelled = true,
+            }
+            if let Some(waker) = seen.waker.take() {
+                waker.wake();
+            }
+            true
+        })
+    }
+
+    fn poll_removed(&mut self, ctx: &mut Context<'_>) -> Poll<bool> {
+        {
+            let mut seen = self.seen.lock().unwrap();
+            if seen.removed || seen.cancelled {
+                return Poll::Ready(seen.removed);
+            }
+            seen.waker = Some(ctx.waker().clone());
+        }
+
+        if self.id.is_none() {
+            if self.map.is_closed() {
+                return Poll::Ready(false);
+            }
+
+            // NB: the listener is registered before looking the key up, so a removal which
+            // happens in between is either seen by the lookup or passed to the listener.
+            let listener = WaitRemoved::<K, V, S>::listener(self.key.clone(), self.seen.clone());
+            let id = *self.id.insert(self.map.removals.add(listener));
+            if !matches!(self.map.map.get(&self.key).as_deref(), Some(Filled(..))) {
+                self.map.removals.remove(id);
+                return Poll::Ready(true);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<'a, K, V, S> Unpin for WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{ }
+
+impl<'a, K, V, S> Future for WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    type Output = bool;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<bool> {
+        assert!(!self.done, "`WaitRemoved` polled after completion");
+        let poll = self.poll_removed(ctx);
+        self.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<'a, K, V, S> FusedFuture for WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq + Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, K, V, S> Drop for WaitRemoved<'a, K, V, S> where
+    K: Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.map.removals.remove(id);
+        }
+    }
+}
+
+/// A future which waits for the first of several keys to be filled.
+///
+/// Created by [`WaitMap::wait_first`](crate::WaitMap::wait_first).
+pub struct WaitFirst<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    map: &'a WaitMap<K, V, S>,
+    keys: &'b [&'b Q],
+    idxs: Vec<usize>,
+    // keep the keys' placeholders in the map until the first poll registers wakers on them
+    reservations: Vec<Option<Arc<()>>>,
+    done: bool,
+}
+
+impl<'a, 'b, K, V, S, Q> WaitFirst<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    pub(crate) fn new(
+        map: &'a WaitMap<K, V, S>,
+        keys: &'b [&'b Q],
+        reservations: Vec<Option<Arc<()>>>,
+    ) -> Self {
+        WaitFirst { map, keys, idxs: vec![usize::MAX; keys.len()], reservations, done: false }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Future for WaitFirst<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    type Output = Option<(usize, Ref<'a, K, V, S>)>;
+
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "`WaitFirst` polled after completion");
+        let this = &mut *self;
+        let map = this.map;
+        // NB: the reservations are only released once this poll returns, by which time every
+        // key which is still waiting has a waker registered on it.
+        let _reservations = mem::take(&mut this.reservations);
+
+        'poll: loop {
+            let mut missing = 0;
+            for (i, &key) in this.keys.iter().enumerate() {
+                match map.poll_ref(key, &mut this.idxs[i], ctx, None) {
+                    Poll::Pending               => {}
+                    Poll::Ready(None)           => missing += 1,
+                    Poll::Ready(Some(entry))    => {
+                        // NB: the other keys may belong to the same shard as this one, so the
+                        // guard has to be released before they are deregistered.
+                        drop(entry);
+                        for (j, &other) in this.keys.iter().enumerate() {
+                            if j != i {
+                                let idx = mem::replace(&mut this.idxs[j], usize::MAX);
+                                map.remove_waker(other, idx);
+                            }
+                        }
+                        match map.poll_ref(key, &mut this.idxs[i], ctx, None) {
+                            Poll::Ready(Some(entry))    => {
+                                // NB: while it waits for a permit, the wait parks on none of
+                                // its keys, and looks them all up again once one is released.
+                                let entry = match map.poll_permit(entry, ctx) {
+                                    Poll::Ready(entry)  => entry,
+                                    Poll::Pending       => return Poll::Pending,
+                                };
+                                this.done = true;
+                                return Poll::Ready(entry.map(|entry| (i, entry)));
+                            }
+                            // removed while the guard was released, start again
+                            _                           => continue 'poll,
+                        }
+                    }
+                }
+            }
+
+            if missing == this.keys.len() {
+                this.done = true;
+                return Poll::Ready(None);
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> FusedFuture for WaitFirst<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn is_terminated(&self) -> bool {
+        self.done
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Drop for WaitFirst<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn drop(&mut self) {
+        for (&key, &idx) in self.keys.iter().zip(&self.idxs) {
+            self.map.drop_waker(key, idx);
+        }
+    }
+}
+
+// A task's registration with a filled key, which `wait_while_async` uses to wait for the key's
+// value to be replaced after rejecting it.
+//
+// The task is registered with the waiters `insert_wake_n` holds back, which are woken when the
+// value is replaced or removed, under the same lock the value is read under, so a value filled
+// in between is never missed. The set's reservation is held on to as well, to tell whether the
+// set the task registered with is still the key's.
+pub(crate) struct Refill<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    map: &'a WaitMap<K, V, S>,
+    key: &'b Q,
+    registered: Option<(Arc<()>, usize)>,
+}
+
+impl<'a, 'b, K, V, S, Q> Refill<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: &'b Q) -> Self {
+        Refill { map, key, registered: None }
+    }
+
+    // Returns a clone of the key's value, registering the task to be woken once it is replaced.
+    pub(crate) fn read(&mut self, ctx: &mut Context<'_>) -> Option<V> where V: Clone {
+        self.deregister();
+        let mut entry = self.map.map.get_mut(self.key)?;
+        let (value, held_back) = match entry.value_mut() {
+            Filled(value, held_back)    => (value.clone(), held_back),
+            Waiting(_)                  => return None,
+        };
+        // a closed map cancels no more waits, so the task is not parked
+        if self.map.is_closed() { return Some(value); }
+
+        let wakers = held_back.get_or_insert_with(|| Box::new(WakerSet::new()));
+        let mut idx = usize::MAX;
+        let evicted = self.map.register(wakers, ctx, &mut idx);
+        self.registered = Some((wakers.reserve_waiter(), idx));
+        drop(entry);
+        if let Some(evicted) = evicted { evicted.wake(); }
+        Some(value)
+    }
+
+    // Resolves to `true` once the value read last has been replaced or removed, or to `false`
+    // if the task's registration was cancelled.
+    pub(crate) fn poll_replaced(&mut self, ctx: &mut Context<'_>) -> Poll<bool> {
+        let (reservation, idx) = match &mut self.registered {
+            Some((reservation, idx))    => (&*reservation, idx),
+            None                        => return Poll::Ready(false),
+        };
+        let mut entry = match self.map.map.get_mut(self.key) {
+            Some(entry) => entry,
+            None        => return Poll::Ready(true),
+        };
+        match entry.value_mut() {
+            Filled(_, Some(wakers)) if wakers.is_reserved_by(reservation) => {
+                if wakers.is_cancelled(*idx) {
+                    Poll::Ready(false)
+                } else if wakers.get(*idx).is_some() {
+                    wakers.replace(ctx.waker().clone(), idx, None);
+                    Poll::Pending
+                } else {
+                    Poll::Ready(true)
+                }
+            }
+            _                                                              => Poll::Ready(true),
+        }
+    }
+
+    fn deregister(&mut self) {
+        let (reservation, idx) = match self.registered.take() {
+            Some(registered)    => registered,
+            None                => return,
+        };
+        if let Some(mut entry) = self.map.map.get_mut(self.key) {
+            if let Filled(_, held_back) = entry.value_mut() {
+                let ours = held_back.as_mut().filter(|wakers| wakers.is_reserved_by(&reservation));
+                if let Some(wakers) = ours {
+                    wakers.remove(idx);
+                    drop(reservation);
+                    if wakers.is_unwaited() {
+                        *held_back = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, 'b, K, V, S, Q> Drop for Refill<'a, 'b, K, V, S, Q> where
+    K: Hash + Eq + Borrow<Q>,
+    S: BuildHasher + Clone,
+    Q: ?Sized + Hash + Eq,
+{
+    fn drop(&mut self) {
+        self.deregister();
+    }
+}
+
+// When a timed wait first parked, and where it finds how many waiters its key had when filled.
+pub(crate) struct Parked {
+    since: Instant,
+    depth: Arc<AtomicUsize>,
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Clone> WaitMap<K, V, S> {
+    // Returns a clone of the first filled pair whose key matches `pred`, if there is one.
+    pub(crate) fn find_filled(&self, pred: &dyn Fn(&K) -> bool) -> Option<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.map.iter().find_map(|entry| match entry.pair() {
+            (key, Filled(value, _)) if pred(key) => Some((key.clone(), value.clone())),
+            _                                    => None,
+        })
+    }
+
+    // Checks whether the key has been filled, registering the waker at `idx` if it has not.
+    pub(crate) fn poll_ref<Q: ?Sized + Hash + Eq>(
+        &self,
+        key: &Q,
+        idx: &mut usize,
+        ctx: &mut Context<'_>,
+        parked: Option<&mut Option<Parked>>,
+    ) -> Poll<Option<Ref<'_, K, V, S>>>
+        where K: Borrow<Q>
+    {
+        // NB: under `max_in_flight`, a filled key is looked up under a read lock first, so that
+        // a task which holds references into the key's shard, as waiting for a permit invites,
+        // does not deadlock taking the write lock.
+        if self.in_flight.is_some() {
+            if let Some(entry) = self.map.get(key) {
+                if let Filled(..) = entry.value() {
+                    *idx = usize::MAX;
+                    return Poll::Ready(Some(self.make_ref(entry)));
+                }
+            }
+        }
+        match self.map.get_mut(key) {
+            Some(mut entry) => match entry.value_mut() {
+                Waiting(wakers) if wakers.is_cancelled(*idx) => {
+                    *idx = usize::MAX;
+                    Poll::Ready(None)
+                }
+                // the map has been shut down, so new waits are turned away
+                Waiting(_) if *idx == usize::MAX && self.is_closed() => Poll::Ready(None),
+                Waiting(wakers)  => {
+                    let evicted = self.register(wakers, ctx, idx);
+                    self.park(wakers, parked);
+                    drop(entry);
+                    if let Some(evicted) = evicted { evicted.wake(); }
+                    Poll::Pending
+                }
+                Filled(..)       => {
+                    let inner = entry.downgrade();
+                    *idx = usize::MAX;
+                    Poll::Ready(Some(self.make_ref(inner)))
+                }
+            }
+            None        => {
+                // the entry is gone, so there is no waker to deregister on drop
+                *idx = usize::MAX;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    // Holds a resolved reference back until it can take a permit, if the map limits how many
+    // may be held at once. The reference is dropped while waiting for a permit, so the key is
+    // looked up again once one is released.
+    pub(crate) fn poll_permit<'a, G: HoldPermit<'a>>(&'a self, mut entry: G, ctx: &mut Context<'_>)
+        -> Poll<Option<G>>
+    {
+        match self.poll_in_flight(ctx) {
+            Poll::Ready(permit) => {
+                if let Some(permit) = permit { entry.hold(permit); }
+                Poll::Ready(Some(entry))
+            }
+            Poll::Pending       => Poll::Pending,
+        }
+    }
+
+    // Takes a permit for a reference about to be resolved, if the map limits how many may be
+    // held at once.
+    pub(crate) fn poll_in_flight(&self, ctx: &mut Context<'_>) -> Poll<Option<Permit<'_>>> {
+        match &self.in_flight {
+            Some(in_flight) => in_flight.poll_acquire(ctx).map(Some),
+            None            => Poll::Ready(None),
+        }
+    }
+
+    // Takes the key's value if it has been filled, registering the waker at `idx` if it has
+    // not.
+    pub(crate) fn poll_take<Q: ?Sized + Hash + Eq>(
+        &self,
+        key: &Q,
+        idx: &mut usize,
+        ctx: &mut Context<'_>,
+        parked: &mut Option<Parked>,
+        filled: Option<&mut Option<Arc<AtomicUsize>>>,
+    ) -> Poll<RemoveOutcome<K, V>>
+        where K: Borrow<Q>
+    {
+        loop {
+            // NB: observing the value and removing it must be a single operation, otherwise a
+            // racing taker could remove it in between, and this future would see it vanish.
+            if let Some((key, Filled(value, _))) = self.map.remove_if(key, |_, entry| {
+                matches!(entry, Filled(..))
+            }) {
+                *idx = usize::MAX;
+                self.count_removal(&key);
+                self.hooks.removed(&key, &value);
+                return Poll::Ready(RemoveOutcome::Removed((key, value)));
+            }
+
+            match self.map.get_mut(key) {
+                Some(mut entry) => match entry.value_mut() {
+                    Waiting(wakers) if wakers.is_cancelled(*idx) => {
+                        *idx = usize::MAX;
+                        return Poll::Ready(RemoveOutcome::KeyGone);
+                    }
+                    Waiting(_) if *idx == usize::MAX && self.is_closed() => {
+                        return Poll::Ready(RemoveOutcome::KeyGone);
+                    }
+                    Waiting(wakers)  => {
+                        let evicted = self.register(wakers, ctx, idx);
+                        self.park(wakers, Some(parked));
+                        if let Some(filled) = filled {
+                            *filled = Some(wakers.depth());
+                        }
+                        drop(entry);
+                        if let Some(evicted) = evicted { evicted.wake(); }
+                        return Poll::Pending;
+                    }
+                    // filled between the two locks, try to take it again
+                    Filled(..)       => continue,
+                }
+                None        => {
+                    // the depth of the key's waiters is only set if it was filled, and this
+                    // taker was one of them, so another task must have taken the value
+                    let registered = mem::replace(idx, usize::MAX) != usize::MAX;
+                    let lost = registered && filled.and_then(|filled| filled.as_ref())
+                        .is_some_and(|depth| depth.load(Ordering::SeqCst) > 0);
+                    let outcome = if lost { RemoveOutcome::Lost } else { RemoveOutcome::KeyGone };
+                    return Poll::Ready(outcome);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn poll_ref_mut<Q: ?Sized + Hash + Eq>(
+        &self,
+        key: &Q,
+        idx: &mut usize,
+        ctx: &mut Context<'_>,
+        parked: Option<&mut Option<Parked>>,
+    ) -> Poll<Option<RefMut<'_, K, V, S>>>
+        where K: Borrow<Q>
+    {
+        match self.map.get_mut(key) {
+            Some(mut entry) => match entry.value_mut() {
+                Waiting(wakers) if wakers.is_cancelled(*idx) => {
+                    *idx = usize::MAX;
+                    Poll::Ready(None)
+                }
+                // the map has been shut down, so new waits are turned away
+                Waiting(_) if *idx == usize::MAX && self.is_closed() => Poll::Ready(None),
+                Waiting(wakers)  => {
+                    let evicted = self.register(wakers, ctx, idx);
+                    self.park(wakers, parked);
+                    drop(entry);
+                    if let Some(evicted) = evicted { evicted.wake(); }
+                    Poll::Pending
+                }
+                Filled(..)       => {
+                    *idx = usize::MAX;
+                    Poll::Ready(Some(self.make_ref_mut(entry)))
+                }
+            }
+            None        => {
+                *idx = usize::MAX;
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    // Registers the task's waker, evicting the oldest waiter on the key if the new one takes it
+    // past `max_waiters_per_key`. The evicted waker is returned rather than woken, so that the
+    // caller can wake it once the shard lock has been released.
+    pub(crate) fn register(&self, wakers: &mut WakerSet, ctx: &mut Context<'_>, idx: &mut usize)
+        -> Option<Waker>
+    {
+        wakers.replace(ctx.waker().clone(), idx, self.max_waiters)
+    }
+
+    // Starts timing a wait which has just registered its waker, unless it is already timed.
+    pub(crate) fn park(&self, wakers: &mut WakerSet, parked: Option<&mut Option<Parked>>) {
+        if let Some(parked) = parked {
+            if parked.is_none() && self.hooks.times_waits() {
+                *parked = Some(Parked { since: Instant::now(), depth: wakers.depth() });
+            }
+        }
+    }
+
+    // Reports how long a wait was parked once it resolves, along with the number of waiters
+    // its key had when it was filled.
+    pub(crate) fn time_wait<T>(&self, parked: &mut Option<Parked>, poll: Poll<Option<T>>)
+        -> Poll<Option<T>>
+    {
+        if let Poll::Ready(output) = &poll {
+            if let Some(parked) = parked.take() {
+                let outcome = match output {
+                    Some(_) => WaitOutcome::Filled { waiters: parked.depth.load(Ordering::SeqCst) },
+                    None    => WaitOutcome::Cancelled,
+                };
+                self.hooks.wait_resolved(parked.since.elapsed(), outcome);
+            }
+        }
+        poll
+    }
+
+    // Returns a clone of the waker registered at `idx`, if the key is still waiting.
+    pub(crate) fn registered_waker<Q: ?Sized + Hash + Eq>(&self, key: &Q, idx: usize)
+        -> Option<Waker>
+        where K: Borrow<Q>
+    {
+        if idx == usize::MAX { return None; }
+        match self.map.get(key)?.value() {
+            Waiting(wakers) => wakers.get(idx).cloned(),
+            Filled(..)      => None,
+        }
+    }
+
+    // Removes the waker registered at `idx`, if the wait has not already completed.
+    pub(crate) fn remove_waker<Q: ?Sized + Hash + Eq>(&self, key: &Q, idx: usize)
+        where K: Borrow<Q>
+    {
+        if idx == usize::MAX { return; }
+        if let Some(mut entry) = self.map.get_mut(key) {
+            if let Waiting(wakers) = entry.value_mut() {
+                wakers.remove(idx);
+            }
+        }
+    }
+
+    // Removes the waker registered at `idx` when a wait is dropped. If no other task has a
+    // waker registered on the key, or a reservation for one, its placeholder is removed as
+    // well.
+    pub(crate) fn drop_waker<Q: ?Sized + Hash + Eq>(&self, key: &Q, idx: usize)
+        where K: Borrow<Q>
+    {
+        if idx == usize::MAX { return; }
+        let unwaited = match self.map.get_mut(key) {
+            Some(mut entry) => match entry.value_mut() {
+                // a cancelled waiter keeps the placeholder, see `cancel_waiters`
+                Waiting(wakers) if wakers.is_cancelled(idx) => false,
+                Waiting(wakers)  => {
+                    wakers.remove(idx);
+                    wakers.is_unwaited()
+                }
+                // a waiter `insert_wake_n` held back is still registered with the key
+                Filled(_, Some(held_back)) => {
+                    held_back.remove(idx);
+                    false
+                }
+                Filled(..)       => false,
+            }
+            None        => false,
+        };
+
+        // NB: another task may have registered itself between the two locks, so the check has
+        // to be repeated under the lock used to remove the placeholder.
+        if unwaited {
+            self.map.remove_if(key, |_, entry| match entry {
+                Waiting(wakers) => wakers.is_unwaited(),
+                Filled(..)      => false,
+            });
+        }
     }
 }
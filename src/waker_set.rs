@@ -1,38 +1,151 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::task::Waker;
 
 use smallvec::SmallVec;
 
 pub struct WakerSet {
     wakers: SmallVec<[Option<Waker>; 1]>,
+    // wakers registered below this index were cancelled without the key being removed
+    cancelled_below: usize,
+    // shared with timed waits, which read how many waiters there were once the key is filled;
+    // it stays at zero if the key is removed without being filled
+    depth: Option<Arc<AtomicUsize>>,
+    // shared with waits which have been created but not polled yet, so that the placeholder
+    // is not removed as unwaited before they get to register
+    reservations: Option<Arc<()>>,
 }
 
 impl WakerSet {
     pub fn new() -> WakerSet {
         WakerSet {
             wakers: SmallVec::new(),
+            cancelled_below: 0,
+            depth: None,
+            reservations: None,
         }
     }
 
-    pub fn replace(&mut self, waker: Waker, idx: &mut usize) {
+    pub fn reserve(&mut self, additional: usize) {
+        self.wakers.reserve(additional);
+    }
+
+    // Registers the waker at `idx`, or at a new index if `idx` is not registered yet. If that
+    // takes the set past `max` waiters, the oldest waiter is cancelled and its waker returned.
+    pub fn replace(&mut self, waker: Waker, idx: &mut usize, max: Option<usize>) -> Option<Waker> {
         let len = self.wakers.len();
         if *idx >= len {
-            debug_assert!(len != std::usize::MAX); // usize::MAX is used as a sentinel
+            debug_assert!(len != usize::MAX); // usize::MAX is used as a sentinel
             *idx = len;
             self.wakers.push(Some(waker));
+            match max {
+                Some(max) if self.len() > max   => self.evict_oldest(),
+                _                               => None,
+            }
         } else {
             self.wakers[*idx] = Some(waker);
+            None
         }
     }
 
+    // NB: waiters only ever get higher indices than the waiters registered before them, and
+    // every empty slot below the oldest waiter belongs to a wait which has completed, so
+    // marking everything up to it as cancelled cancels only the oldest waiter.
+    fn evict_oldest(&mut self) -> Option<Waker> {
+        let idx = self.wakers.iter().position(Option::is_some)?;
+        self.cancelled_below = idx + 1;
+        self.wakers[idx].take()
+    }
+
+    // NB: the slot is emptied rather than removed, so the indices held by other waiters never
+    // shift and a slot is never handed to a different waiter.
     pub fn remove(&mut self, idx: usize) {
         self.wakers[idx] = None;
     }
 
-    pub fn wake(self) {
-        for waker in self.wakers {
-            if let Some(waker) = waker {
-                waker.wake()
-            }
+    pub fn get(&self, idx: usize) -> Option<&Waker> {
+        self.wakers.get(idx)?.as_ref()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.wakers.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.wakers.iter().filter(|waker| waker.is_some()).count()
+    }
+
+    // Reserves the key for a wait which will register a waker once it is polled. The key counts
+    // as waited on until the returned reservation is dropped.
+    pub fn reserve_waiter(&mut self) -> Arc<()> {
+        self.reservations.get_or_insert_with(Default::default).clone()
+    }
+
+    // Whether `reservation` was handed out by this set, rather than by one which replaced it.
+    pub fn is_reserved_by(&self, reservation: &Arc<()>) -> bool {
+        matches!(&self.reservations, Some(reserved) if Arc::ptr_eq(reserved, reservation))
+    }
+
+    // Whether no task is waiting on the key, nor about to.
+    pub fn is_unwaited(&self) -> bool {
+        self.len() == 0 && match &self.reservations {
+            Some(reserved)  => Arc::strong_count(reserved) == 1,
+            None            => true,
+        }
+    }
+
+    pub fn depth(&mut self) -> Arc<AtomicUsize> {
+        self.depth.get_or_insert_with(Default::default).clone()
+    }
+
+    // Wakes the waiters of a key which has been filled, recording how many there were.
+    pub fn wake(mut self) {
+        if let Some(depth) = &self.depth {
+            depth.store(self.len(), Ordering::SeqCst);
+        }
+        for waker in self.wakers.drain(..).flatten() {
+            waker.wake()
+        }
+    }
+
+    // Takes up to `n` of the wakers of a key which has been filled, oldest first, so that they
+    // can be woken once the shard's lock is released. The rest stay registered.
+    pub fn take_oldest(&mut self, n: usize) -> Vec<Waker> {
+        if let Some(depth) = &self.depth {
+            depth.store(self.len(), Ordering::SeqCst);
+        }
+        self.wakers.iter_mut().filter_map(Option::take).take(n).collect()
+    }
+
+    // Wakes the waiters of a key which was removed before it was filled. The depth is left at
+    // zero, which is how a woken taker tells a cancelled key from one taken before it polled.
+    pub fn wake_removed(self) {
+        drop(self)
+    }
+
+    // Takes out every registered waker to be woken, so that their tasks see their waits as
+    // cancelled the next time they poll.
+    pub fn cancel(&mut self) -> WakerSet {
+        self.cancelled_below = self.wakers.len();
+        WakerSet {
+            wakers: self.wakers.iter_mut().map(Option::take).collect(),
+            cancelled_below: 0,
+            depth: None,
+            reservations: None,
+        }
+    }
+
+    pub fn is_cancelled(&self, idx: usize) -> bool {
+        idx < self.cancelled_below
+    }
+}
+
+// A set which is dropped without being woken, for example because the map itself was dropped,
+// still wakes its waiters so that they see their key missing instead of waiting forever.
+impl Drop for WakerSet {
+    fn drop(&mut self) {
+        for waker in self.wakers.drain(..).flatten() {
+            waker.wake()
         }
     }
 }
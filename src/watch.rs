@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::hash::{Hash, BuildHasher};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+
+use crate::WaitMap;
+use crate::listen::Listener;
+
+/// A stream of the values a key is filled with.
+///
+/// Created by [`WaitMap::get_or_subscribe`](crate::WaitMap::get_or_subscribe). Every time the
+/// key is filled, whether it was waiting or already filled, the stream yields a clone of the
+/// new value. Removing the key is not reported. The stream ends once the map's waits are
+/// cancelled by `cancel_all` or a shutdown.
+///
+/// Values are queued until the stream is polled, so a watcher which is never polled holds on
+/// to every value its key is filled with.
+///
+/// Watchers are not tied to their key's entry, so while any watcher is alive, every insert
+/// into the map takes a map-wide lock to offer the new pair to them. This happens before the
+/// inserted key's shard is unlocked, which is what guarantees no value is missed, but it also
+/// means inserts into different shards are serialized for as long as a watcher is alive.
+/// Dropping watchers which are no longer needed removes this cost.
+pub struct Watcher<'a, K, V, S> {
+    map: &'a WaitMap<K, V, S>,
+    id: Option<usize>,
+    watched: Arc<Mutex<Watched<V>>>,
+}
+
+// What the listener of a `Watcher` has seen but the watcher has not yet yielded.
+struct Watched<V> {
+    values: VecDeque<V>,
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+impl<'a, K, V, S> Watcher<'a, K, V, S> where
+    K: Hash + Eq + Send + 'static,
+    V: Clone + Send + 'static,
+    S: BuildHasher + Clone,
+{
+    // NB: this must be called under the lock of the key's shard, so that no value the key is
+    // filled with after the caller read it is missed.
+    pub(crate) fn new(map: &'a WaitMap<K, V, S>, key: K) -> Self {
+        // a map which has been shut down has no waits left to cancel, so it would never end
+        let cancelled = map.is_closed();
+        let watched = Arc::new(Mutex::new(Watched {
+            values: VecDeque::new(),
+            cancelled,
+            waker: None,
+        }));
+        let mut watcher = Watcher { map, id: None, watched };
+        if !cancelled {
+            let listener = Watcher::<K, V, S>::listener(key, watcher.watched.clone());
+            watcher.id = Some(map.listeners.add(listener));
+        }
+        watcher
+    }
+
+    fn listener(key: K, watched: Arc<Mutex<Watched<V>>>) -> Listener<K, V> {
+        Box::new(move |filled| {
+            let mut watched = watched.lock().unwrap();
+            match filled {
+                Some((filled, value)) if *filled == key => {
+                    watched.values.push_back(value.clone());
+                }
+                Some(_)                                 => return false,
+                None                                    => watched.cancelled = true,
+            }
+            if let Some(waker) = watched.waker.take() {
+                waker.wake();
+            }
+            watched.cancelled
+        })
+    }
+}
+
+impl<'a, K, V, S> Unpin for Watcher<'a, K, V, S> { }
+
+impl<'a, K, V, S> Stream for Watcher<'a, K, V, S> {
+    type Item = V;
+
+    fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<V>> {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(value) = watched.values.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+        if watched.cancelled {
+            return Poll::Ready(None);
+        }
+        watched.waker = Some(ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<'a, K, V, S> Drop for Watcher<'a, K, V, S> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.map.listeners.remove(id);
+        }
+    }
+}
@@ -99,3 +99,1202 @@ fn multiple_tasks_can_wait_one_key() {
     task::block_on(handle1);
     task::block_on(handle2);
 }
+
+#[test]
+fn take_leaves_waiters_alone() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let handle = task::spawn(async move {
+        let rosa = map.wait("Rosa Luxemburg").await;
+        assert_eq!(rosa.unwrap().value(), &1);
+    });
+
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        assert!(map2.take("Rosa Luxemburg").is_none());
+        map2.insert(String::from("Rosa Luxemburg"), 1);
+    });
+
+    task::block_on(handle);
+}
+
+#[test]
+fn remove_cancels_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let handle = task::spawn(async move {
+        assert!(map.wait("Rosa Luxemburg").await.is_none());
+    });
+
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        assert!(map2.remove("Rosa Luxemburg").is_none());
+    });
+
+    task::block_on(handle);
+}
+
+#[test]
+fn insert_sorted_wakes_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let handle = task::spawn(async move {
+        for i in 0..10 {
+            let value = map.wait(&i.to_string()[..]).await;
+            assert_eq!(value.unwrap().value(), &(i * 2));
+        }
+    });
+
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map2.insert_sorted((0..10).map(|i| (i.to_string(), i * 2)));
+    });
+
+    task::block_on(handle);
+}
+
+#[test]
+fn only_one_wait_take_gets_value() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let handles: Vec<_> = (0..5).map(|_| {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait_take("Rosa Luxemburg").await
+        })
+    }).collect();
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map.insert(String::from("Rosa Luxemburg"), 0);
+
+        let mut taken = 0;
+        for handle in handles {
+            if let Some((key, value)) = handle.await {
+                assert_eq!((&key[..], value), ("Rosa Luxemburg", 0));
+                taken += 1;
+            }
+        }
+        assert_eq!(taken, 1);
+        assert!(map.get("Rosa Luxemburg").is_none());
+    });
+}
+
+#[test]
+fn iter_waiting_reports_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    map.insert(String::from("Rosa Luxemburg"), 0);
+
+    for name in &["Voltairine de Cleyre", "Emma Goldman", "Emma Goldman"] {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait(*name).await;
+        });
+    }
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        let mut waiting: Vec<(String, usize)> = map.iter_waiting().map(|w| {
+            let (key, waiters) = w.pair();
+            (key.clone(), waiters)
+        }).collect();
+        waiting.sort();
+        assert_eq!(waiting, vec![
+            (String::from("Emma Goldman"), 2),
+            (String::from("Voltairine de Cleyre"), 1),
+        ]);
+        map.cancel_all();
+    });
+}
+
+#[test]
+fn hooks_observe_lifecycle() {
+    use std::sync::Mutex;
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let (log1, log2, log3) = (log.clone(), log.clone(), log.clone());
+    let map: WaitMap<String, i32> = WaitMap::new()
+        .on_insert(move |key, value| log1.lock().unwrap().push(format!("insert {} {}", key, value)))
+        .on_remove(move |key, value| log2.lock().unwrap().push(format!("remove {} {}", key, value)))
+        .on_cancel(move |key| log3.lock().unwrap().push(format!("cancel {}", key)));
+
+    task::block_on(async {
+        map.insert(String::from("Rosa Luxemburg"), 0);
+        map.take("Rosa Luxemburg");
+        let wait_fut = map.wait("Voltairine de Cleyre");
+        map.cancel_all();
+        assert!(wait_fut.await.is_none());
+    });
+
+    assert_eq!(*log.lock().unwrap(), vec![
+        String::from("insert Rosa Luxemburg 0"),
+        String::from("remove Rosa Luxemburg 0"),
+        String::from("cancel Voltairine de Cleyre"),
+    ]);
+}
+
+#[cfg(feature = "guard-timing")]
+#[test]
+#[should_panic(expected = "longer than the limit")]
+fn guard_held_too_long_panics() {
+    let map: WaitMap<String, i32> = WaitMap::new().guard_limit(Duration::from_millis(10));
+    map.insert(String::from("Rosa Luxemburg"), 0);
+
+    let rosa = map.get("Rosa Luxemburg");
+    std::thread::sleep(Duration::from_millis(50));
+    drop(rosa);
+}
+
+#[test]
+fn remove_matching_removes_one_prefix() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    for i in 0..5 {
+        map.insert(format!("anarchist/{}", i), i);
+        map.insert(format!("syndicalist/{}", i), i);
+    }
+    let wait_fut = map.wait("anarchist/waiting");
+
+    assert_eq!(map.remove_matching(|key| key.starts_with("anarchist/")), 5);
+
+    for i in 0..5 {
+        assert!(map.get(&format!("anarchist/{}", i)[..]).is_none());
+        assert!(map.get(&format!("syndicalist/{}", i)[..]).is_some());
+    }
+
+    map.insert(String::from("anarchist/waiting"), 0);
+    assert_eq!(task::block_on(wait_fut).unwrap().value(), &0);
+}
+
+#[test]
+fn drain_stream_cancels_waiters() {
+    use async_std::prelude::*;
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let handle = task::spawn(async move {
+        assert!(map.wait("Voltairine de Cleyre").await.is_none());
+    });
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map2.insert(String::from("Rosa Luxemburg"), 0);
+        let drained: Vec<_> = map2.drain_stream().collect().await;
+        assert_eq!(drained, vec![(String::from("Rosa Luxemburg"), 0)]);
+        handle.await;
+    });
+}
+
+#[test]
+fn wait_mut_or_insert_default_fills_once() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let waiter = task::spawn(async move {
+        let rosa = map2.wait("Rosa Luxemburg").await;
+        assert_eq!(rosa.unwrap().value(), &1);
+    });
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(50)).await;
+        let timeout = Duration::from_millis(50);
+
+        let mut rosa = map.wait_mut_or_insert_default("Rosa Luxemburg", timeout).await;
+        assert_eq!(rosa.value(), &0);
+        *rosa.value_mut() += 1;
+        drop(rosa);
+        waiter.await;
+
+        let rosa = map.wait_mut_or_insert_default("Rosa Luxemburg", timeout).await;
+        assert_eq!(rosa.value(), &1);
+    });
+}
+
+#[test]
+fn entry_removal_leaves_waiters_alone() {
+    use waitmap::Entry;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Rosa Luxemburg"), 0);
+    let wait_fut = map.wait("Voltairine de Cleyre");
+
+    match map.entry(String::from("Rosa Luxemburg")) {
+        Entry::Occupied(entry)  => assert_eq!(entry.take(), 0),
+        Entry::Vacant(_)        => panic!(),
+    }
+    assert!(map.get("Rosa Luxemburg").is_none());
+
+    let removed = map.entry(String::from("Voltairine de Cleyre")).and_remove_if(|_, _| true);
+    assert!(removed.is_none());
+
+    map.entry(String::from("Voltairine de Cleyre")).or_insert(1);
+    assert_eq!(task::block_on(wait_fut).unwrap().value(), &1);
+}
+
+#[test]
+fn reserve_all_creates_placeholders() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let names = ["Rosa Luxemburg", "Voltairine de Cleyre", "Emma Goldman"];
+    map.insert(String::from("Emma Goldman"), 2);
+
+    map.reserve_all(names.iter().map(|name| name.to_string()));
+
+    assert!(map.contains_waiting("Rosa Luxemburg"));
+    assert!(map.contains_waiting("Voltairine de Cleyre"));
+    assert!(!map.contains_waiting("Emma Goldman"));
+
+    let map2 = map.clone();
+    task::spawn(async move {
+        map2.insert(String::from("Rosa Luxemburg"), 0);
+        map2.insert(String::from("Voltairine de Cleyre"), 1);
+    });
+
+    task::block_on(async move {
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(map.wait(*name).await.unwrap().value(), &(i as i32));
+        }
+    });
+}
+
+#[test]
+fn approx_memory_bytes_grows_with_entries() {
+    let map: WaitMap<String, [u8; 64]> = WaitMap::new();
+    let empty = map.approx_memory_bytes();
+
+    for i in 0..100 {
+        map.insert(i.to_string(), [0; 64]);
+    }
+    assert!(map.approx_memory_bytes() >= empty + 100 * 64);
+}
+
+#[test]
+fn poll_get_registers_and_deregisters() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct GetValue<'a> {
+        map: &'a WaitMap<String, i32>,
+        slot: Option<usize>,
+    }
+
+    impl Future for GetValue<'_> {
+        type Output = Option<i32>;
+        fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<i32>> {
+            let this = &mut *self;
+            this.map.poll_get("Rosa Luxemburg", ctx, &mut this.slot).map(|r| r.map(|r| *r.value()))
+        }
+    }
+
+    impl Drop for GetValue<'_> {
+        fn drop(&mut self) {
+            self.map.deregister("Rosa Luxemburg", &mut self.slot);
+        }
+    }
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    assert!(task::block_on(GetValue { map: &map, slot: None }).is_none());
+
+    let _reserved = map.wait("Rosa Luxemburg");
+    let map2 = map.clone();
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map2.insert(String::from("Rosa Luxemburg"), 0);
+    });
+    assert_eq!(task::block_on(GetValue { map: &map, slot: None }), Some(0));
+}
+
+#[test]
+fn wait_handle_cancels_one_waiter() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let map1 = map.clone();
+    let cancelled = task::spawn(async move {
+        let (wait_fut, handle) = map1.wait_with_handle("Emma Goldman");
+        task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            handle.cancel();
+        });
+        wait_fut.await.is_none()
+    });
+
+    let map2 = map.clone();
+    let waiting = task::spawn(async move {
+        map2.wait("Emma Goldman").await.map(|r| *r.value())
+    });
+
+    assert!(task::block_on(cancelled));
+    assert_eq!(map.iter_waiting().next().unwrap().key(), "Emma Goldman");
+    map.insert(String::from("Emma Goldman"), 0);
+    assert_eq!(task::block_on(waiting), Some(0));
+}
+
+#[test]
+fn wait_resolved_reports_cancellation() {
+    use std::sync::Mutex;
+    use waitmap::WaitOutcome;
+
+    let outcomes = Arc::new(Mutex::new(vec![]));
+    let recorded = outcomes.clone();
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new()
+        .on_wait_resolved(move |parked, outcome| recorded.lock().unwrap().push((parked, outcome))));
+
+    let map1 = map.clone();
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(50)).await;
+        map1.cancel("Voltairine de Cleyre");
+    });
+
+    assert!(task::block_on(map.wait("Voltairine de Cleyre")).is_none());
+    let outcomes = outcomes.lock().unwrap();
+    assert_eq!(outcomes.len(), 1);
+    assert!(outcomes[0].0 >= Duration::from_millis(40));
+    assert_eq!(outcomes[0].1, WaitOutcome::Cancelled);
+}
+
+#[test]
+fn wait_resolved_reports_queue_depth() {
+    use std::sync::Mutex;
+    use waitmap::WaitOutcome;
+
+    let outcomes = Arc::new(Mutex::new(vec![]));
+    let recorded = outcomes.clone();
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new()
+        .on_wait_resolved(move |_, outcome| recorded.lock().unwrap().push(outcome)));
+
+    let waiters: Vec<_> = (0..3).map(|_| {
+        let map = map.clone();
+        task::spawn(async move { map.wait("Errico Malatesta").await.is_some() })
+    }).collect();
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    map.insert(String::from("Errico Malatesta"), 0);
+    for waiter in waiters {
+        assert!(task::block_on(waiter));
+    }
+
+    assert_eq!(*outcomes.lock().unwrap(), vec![WaitOutcome::Filled { waiters: 3 }; 3]);
+}
+
+#[test]
+fn wait_futures_are_fused() {
+    use futures_core::future::FusedFuture;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Rosa Luxemburg"), 0);
+
+    let (mut wait_fut, _) = map.reserve_wait("Rosa Luxemburg");
+    assert!(!wait_fut.is_terminated());
+    assert_eq!(task::block_on(&mut wait_fut).unwrap().value(), &0);
+    assert!(wait_fut.is_terminated());
+}
+
+#[test]
+fn fill_counts_woken_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let handles: Vec<_> = (0..3).map(|_| {
+        let map = map.clone();
+        task::spawn(async move { map.wait("Errico Malatesta").await.map(|r| *r.value()) })
+    }).collect();
+    task::block_on(task::sleep(Duration::from_millis(100)));
+
+    let (mut value, woken) = map.fill(String::from("Errico Malatesta"), 0);
+    *value += 1;
+    drop(value);
+    assert_eq!(woken, 3);
+    for handle in handles {
+        assert_eq!(task::block_on(handle), Some(1));
+    }
+}
+
+#[test]
+fn wait_first_prefers_earlier_keys() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Louise Michel"), 1);
+    map.insert(String::from("Emma Goldman"), 0);
+
+    let (idx, entry) = task::block_on(map.wait_first(&["Emma Goldman", "Louise Michel"])).unwrap();
+    assert_eq!((idx, *entry.value()), (0, 0));
+    drop(entry);
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+    task::spawn(async move {
+        task::sleep(Duration::from_millis(50)).await;
+        map2.cancel_all();
+    });
+    assert!(task::block_on(map.wait_first(&["Emma Goldman", "Louise Michel"])).is_none());
+}
+
+#[test]
+fn extract_if_takes_even_values() {
+    let map: WaitMap<i32, i32> = WaitMap::new();
+    map.insert_sorted((0..10).map(|i| (i, i)));
+    let _wait_fut = map.wait_eq(10);
+
+    let mut extracted = map.extract_if(|_, value| value % 2 == 0);
+    extracted.sort();
+    assert_eq!(extracted, vec![(0, 0), (2, 2), (4, 4), (6, 6), (8, 8)]);
+    assert!((0..10).all(|i| map.get(&i).is_some() == (i % 2 == 1)));
+    assert!(map.contains_waiting(&10));
+}
+
+#[test]
+fn dropping_last_waiter_removes_placeholder() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+
+    let (_, elapsed) = task::block_on(map.wait_timed("Gustav Landauer", Duration::from_millis(10)));
+    assert!(elapsed >= Duration::from_millis(10));
+    assert!(!map.contains_waiting("Gustav Landauer"));
+
+    let map = Arc::new(map);
+    let map1 = map.clone();
+    let handle = task::spawn(async move { map1.wait("Gustav Landauer").await.map(|r| *r.value()) });
+    task::block_on(map.wait_timed("Gustav Landauer", Duration::from_millis(50)));
+    assert!(map.contains_waiting("Gustav Landauer"));
+
+    map.insert(String::from("Gustav Landauer"), 0);
+    assert_eq!(task::block_on(handle), Some(0));
+}
+
+#[test]
+fn into_values_skips_waiting_keys() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Rosa Luxemburg"), 0);
+    map.insert(String::from("Emma Goldman"), 1);
+    let (_, created) = map.reserve_wait("Voltairine de Cleyre");
+    assert!(created);
+
+    let mut values: Vec<i32> = map.into_values().collect();
+    values.sort();
+    assert_eq!(values, vec![0, 1]);
+}
+
+#[test]
+fn dropped_waits_do_not_lose_wakeups() {
+    use async_std::future::timeout;
+
+    for round in 0..200 {
+        let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+        let map1 = map.clone();
+        let steady = task::spawn(async move {
+            map1.wait("Nicola Sacco").await.map(|r| *r.value())
+        });
+
+        // these waits lose the race against their timer over and over, dropping their futures
+        // mid-flight the way `select!` does, until the key is finally filled
+        let impatient: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            task::spawn(async move {
+                loop {
+                    let wait_fut = map.wait("Nicola Sacco");
+                    if let Ok(value) = timeout(Duration::from_micros(200), wait_fut).await {
+                        break value.map(|r| *r.value());
+                    }
+                }
+            })
+        }).collect();
+
+        let map2 = map.clone();
+        task::block_on(async move {
+            task::sleep(Duration::from_micros(100 * (round % 10))).await;
+            map2.insert(String::from("Nicola Sacco"), round as i32);
+        });
+
+        assert_eq!(task::block_on(steady), Some(round as i32));
+        for waiter in impatient {
+            assert_eq!(task::block_on(waiter), Some(round as i32));
+        }
+        assert!(!map.contains_waiting("Nicola Sacco"));
+    }
+}
+
+#[test]
+fn shard_view_insert_wakes_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let map1 = map.clone();
+    let waiting = task::spawn(async move {
+        map1.wait("Gustav Landauer").await.map(|r| *r.value())
+    });
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    let shard = map.shard_index("Gustav Landauer");
+    map.with_shard(shard, |mut view| {
+        assert!(view.get("Gustav Landauer").is_none());
+        assert!(view.insert(String::from("Gustav Landauer"), 1870).is_none());
+        *view.get_mut("Gustav Landauer").unwrap() += 49;
+    });
+    assert_eq!(task::block_on(waiting), Some(1919));
+}
+
+#[test]
+fn max_waiters_evicts_oldest_waiter() {
+    let map: WaitMap<String, i32> = WaitMap::builder().max_waiters_per_key(2).build();
+    let map = Arc::new(map);
+
+    let mut waiters = vec![];
+    for _ in 0..3 {
+        let map = map.clone();
+        waiters.push(task::spawn(async move {
+            map.wait("Sébastien Faure").await.map(|r| *r.value())
+        }));
+        task::block_on(task::sleep(Duration::from_millis(20)));
+    }
+
+    map.insert(String::from("Sébastien Faure"), 0);
+    let results: Vec<_> = waiters.into_iter().map(task::block_on).collect();
+    assert_eq!(results, vec![None, Some(0), Some(0)]);
+}
+
+#[test]
+fn evicted_waiter_is_woken_outside_the_shard_lock() {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Wake, Waker};
+
+    // looks the key up when woken, which deadlocks if the shard is still locked
+    struct LookUp(Arc<WaitMap<String, i32>>, AtomicBool);
+
+    impl Wake for LookUp {
+        fn wake(self: Arc<Self>) {
+            assert!(self.0.contains_waiting("Emile Pouget"));
+            self.1.store(true, Ordering::SeqCst);
+        }
+    }
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let map: WaitMap<String, i32> = WaitMap::builder().max_waiters_per_key(1).build();
+    let map = Arc::new(map);
+    let look_up = Arc::new(LookUp(map.clone(), AtomicBool::new(false)));
+
+    let mut oldest = Box::pin(map.wait("Emile Pouget"));
+    let waker = Waker::from(look_up.clone());
+    assert!(oldest.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+
+    let mut newest = Box::pin(map.wait("Emile Pouget"));
+    let noop = Waker::from(Arc::new(Noop));
+    assert!(newest.as_mut().poll(&mut Context::from_waker(&noop)).is_pending());
+    assert!(look_up.1.load(Ordering::SeqCst));
+}
+
+#[test]
+fn insert_full_returns_old_value_and_guard() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Rosa Luxemburg"), 0);
+
+    let (old, mut new) = map.insert_full(String::from("Rosa Luxemburg"), 1);
+    assert_eq!(old, Some(0));
+    assert_eq!(new.pair(), (&String::from("Rosa Luxemburg"), &1));
+    *new.value_mut() += 1;
+    drop(new);
+
+    assert_eq!(map.get("Rosa Luxemburg").unwrap().value(), &2);
+}
+
+#[test]
+fn wait_matching_ignores_other_keys() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let map1 = map.clone();
+    let matching = task::spawn(async move {
+        map1.wait_matching(|key| key.starts_with("Mujeres Libres/")).await
+    });
+    let map2 = map.clone();
+    let cancelled = task::spawn(async move {
+        map2.wait_matching(|key| key.starts_with("CNT/")).await
+    });
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    map.insert(String::from("FAI/Federica Montseny"), 0);
+    map.insert(String::from("Mujeres Libres/Lucía Sánchez Saornil"), 1);
+    assert_eq!(
+        task::block_on(matching),
+        Some((String::from("Mujeres Libres/Lucía Sánchez Saornil"), 1)),
+    );
+
+    map.cancel_all();
+    assert_eq!(task::block_on(cancelled), None);
+}
+
+#[test]
+fn remove_before_woken_waiter_polls() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    task::block_on(async {
+        // polled after the key was filled and removed again
+        let mut late = Box::pin(map.wait("Ferdinand Domela Nieuwenhuis"));
+        assert!(poll_fn(|ctx| Poll::Ready(late.as_mut().poll(ctx).is_pending())).await);
+        map.insert(String::from("Ferdinand Domela Nieuwenhuis"), 0);
+        assert_eq!(map.remove("Ferdinand Domela Nieuwenhuis"), Some(0));
+        assert!(late.await.is_none());
+
+        // polled while the key was filled
+        let mut early = Box::pin(map.wait("Ferdinand Domela Nieuwenhuis"));
+        assert!(poll_fn(|ctx| Poll::Ready(early.as_mut().poll(ctx).is_pending())).await);
+        map.insert(String::from("Ferdinand Domela Nieuwenhuis"), 1);
+        assert_eq!(early.await.map(|r| *r.value()), Some(1));
+        assert_eq!(map.remove("Ferdinand Domela Nieuwenhuis"), Some(1));
+    });
+}
+
+#[test]
+fn timed_out_waits_leave_no_placeholders() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+
+    task::block_on(async {
+        for i in 0..100 {
+            let key = format!("Haymarket {}", i);
+            let (value, _) = map.wait_timed(&key[..], Duration::from_millis(1)).await;
+            assert!(value.is_none());
+        }
+    });
+    assert_eq!(map.iter_waiting().count(), 0);
+}
+
+#[test]
+fn refresh_returns_displaced_values() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert_sorted(vec![(String::from("Emma Goldman"), 1), (String::from("Lucy Parsons"), 2)]);
+
+    let old = map.insert_many_returning_old(vec![
+        (String::from("Lucy Parsons"), 20),
+        (String::from("Emma Goldman"), 10),
+        (String::from("Louise Michel"), 30),
+    ]);
+    assert_eq!(old, vec![
+        (String::from("Lucy Parsons"), Some(2)),
+        (String::from("Emma Goldman"), Some(1)),
+        (String::from("Louise Michel"), None),
+    ]);
+    assert_eq!(map.get("Emma Goldman").unwrap().value(), &10);
+}
+
+#[test]
+fn wait_take_detailed_reports_lost_races() {
+    use waitmap::RemoveOutcome;
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let handles: Vec<_> = (0..5).map(|_| {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait_take_detailed("Rosa Luxemburg").await
+        })
+    }).collect();
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map.insert(String::from("Rosa Luxemburg"), 0);
+
+        let (mut removed, mut lost) = (0, 0);
+        for handle in handles {
+            match handle.await {
+                RemoveOutcome::Removed(_)   => removed += 1,
+                RemoveOutcome::Lost         => lost += 1,
+                RemoveOutcome::KeyGone      => panic!("the key was filled"),
+            }
+        }
+        assert_eq!((removed, lost), (1, 4));
+
+        let gone = map.wait_take_detailed("Rosa Luxemburg");
+        assert!(map.cancel("Rosa Luxemburg"));
+        assert_eq!(gone.await, RemoveOutcome::KeyGone);
+    });
+}
+
+#[test]
+fn cancel_token_cancels_parked_waits() {
+    use waitmap::CancelToken;
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let token = CancelToken::new();
+
+    let handles: Vec<_> = (0..5).map(|i| {
+        let (map, token) = (map.clone(), token.clone());
+        task::spawn(async move {
+            map.wait_with_token(&format!("Paris Commune {}", i)[..], &token).await.is_none()
+        })
+    }).collect();
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        token.cancel();
+        for handle in handles {
+            assert!(handle.await);
+        }
+
+        // waits created after the token was cancelled give up, even on filled keys
+        map.insert(String::from("Paris Commune 0"), 1871);
+        assert!(map.wait_with_token("Paris Commune 0", &token).await.is_none());
+    });
+}
+
+#[test]
+fn wait_until_or_deregisters_when_cancelled() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+
+    task::block_on(async {
+        let shutdown = task::sleep(Duration::from_millis(20));
+        assert!(map.wait_until_or("Mollie Steimer", shutdown).await.is_none());
+        assert!(!map.contains_waiting("Mollie Steimer"));
+
+        let mut other = Box::pin(map.wait("Mollie Steimer"));
+        assert!(poll_fn(|ctx| Poll::Ready(other.as_mut().poll(ctx).is_pending())).await);
+        let shutdown = task::sleep(Duration::from_millis(20));
+        assert!(map.wait_until_or("Mollie Steimer", shutdown).await.is_none());
+        assert!(map.contains_waiting("Mollie Steimer"));
+        drop(other);
+    });
+}
+
+#[test]
+fn len_matches_a_full_scan() {
+    use waitmap::Entry;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    let check = |map: &WaitMap<String, i32>| assert_eq!(map.len(), map.snapshot().len());
+    let key = |i: i32| format!("Kronstadt {}", i);
+
+    let wait_fut = map.wait("Kronstadt 0");
+    check(&map);
+    map.insert(key(0), 0);
+    map.insert(key(0), 1);
+    drop(wait_fut);
+    drop(map.fill(key(1), 1));
+    drop(map.insert_full(key(2), 2));
+    map.insert_with(key(3), || 3);
+    let _wait_fut = map.wait("Kronstadt 4");
+    assert!(map.insert_with_if_waiting(key(4), || 4));
+    map.insert_sorted((5..10).map(|i| (key(i), i)));
+    map.insert_many_returning_old((8..12).map(|i| (key(i), i)));
+    drop(map.insert_if_absent(key(12), 12));
+    drop(map.get_or_default("Kronstadt 13"));
+    let _wait_fut = map.wait("Kronstadt 14");
+    map.entry(key(14)).and_modify_or_fill(|value| *value += 1, || 14);
+    check(&map);
+    assert_eq!(map.len(), 15);
+
+    assert_eq!(map.remove("Kronstadt 0"), Some(1));
+    assert_eq!(map.take("Kronstadt 1"), Some(1));
+    assert!(map.remove_entry("Kronstadt 2").is_some());
+    assert_eq!(map.remove_matching(|key| key == "Kronstadt 3"), 1);
+    assert_eq!(map.extract_if(|_, value| *value == 4).len(), 1);
+    if let Entry::Occupied(entry) = map.entry(key(5)) {
+        entry.remove();
+    }
+    if let Entry::Occupied(entry) = map.entry(key(6)) {
+        drop(entry.replace_entry_with(|_, _| None));
+    }
+    let shard = map.shard_index("Kronstadt 7");
+    map.with_shard(shard, |mut view| {
+        view.remove("Kronstadt 7");
+        view.insert(key(7), 7);
+        view.insert(key(7), 8);
+    });
+    task::block_on(async {
+        assert!(map.wait_take("Kronstadt 8").await.is_some());
+    });
+    check(&map);
+    assert_eq!(map.len(), 7);
+
+    let (drained, _) = map.take_all();
+    assert_eq!(drained.len(), 7);
+    check(&map);
+    assert!(map.is_empty());
+}
+
+#[test]
+fn drain_waiting_returns_cancelled_keys() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let names = ["Francisco Ferrer", "Teresa Claramunt", "Anselmo Lorenzo"];
+
+    let handles: Vec<_> = names.iter().copied().map(|name| {
+        let map = map.clone();
+        task::spawn(async move { map.wait(name).await.is_none() })
+    }).collect();
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map.insert(String::from("Ricardo Mella"), 1861);
+
+        let mut keys = map.drain_waiting();
+        keys.sort();
+        let mut expected: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        for handle in handles {
+            assert!(handle.await);
+        }
+        assert!(map.drain_waiting().is_empty());
+        assert_eq!(map.len(), 1);
+    });
+}
+
+#[test]
+fn bulk_inserts_wake_every_waiter() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let handles: Vec<_> = (0..40).map(|i| {
+        let map = map.clone();
+        task::spawn(async move {
+            let value = map.wait(&format!("Ukraine {}", i % 20)[..]).await;
+            *value.unwrap().value()
+        })
+    }).collect();
+
+    task::block_on(async move {
+        task::sleep(Duration::from_millis(140)).await;
+        map.insert_sorted((0..10).map(|i| (format!("Ukraine {}", i), i)));
+        map.insert_many_returning_old((10..20).map(|i| (format!("Ukraine {}", i), i)));
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.await, i as i32 % 20);
+        }
+        assert_eq!(map.iter_waiting().count(), 0);
+    });
+}
+
+#[test]
+fn get_or_subscribe_misses_no_updates() {
+    use async_std::prelude::*;
+
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let map2 = map.clone();
+
+    let writer = task::spawn(async move {
+        for i in 0..1000 {
+            map2.insert(String::from("Alexander Berkman"), i);
+            if i % 100 == 0 { task::yield_now().await; }
+        }
+    });
+
+    task::block_on(async move {
+        let (current, mut watcher) = map.get_or_subscribe("Alexander Berkman");
+        let mut next = current.map_or(0, |current| current + 1);
+        while next < 1000 {
+            assert_eq!(watcher.next().await, Some(next));
+            next += 1;
+        }
+        writer.await;
+    });
+}
+
+#[test]
+fn max_in_flight_bounds_held_refs() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::builder().max_in_flight(2).build();
+    map.insert_sorted((0..3).map(|i| (format!("Spain {}", i), i)));
+
+    task::block_on(async {
+        let first = map.wait("Spain 0").await.unwrap();
+        let second = map.wait("Spain 1").await.unwrap();
+
+        let mut third = Box::pin(map.wait("Spain 2"));
+        assert!(poll_fn(|ctx| Poll::Ready(third.as_mut().poll(ctx).is_pending())).await);
+
+        drop(first);
+        assert_eq!(*third.await.unwrap().value(), 2);
+        assert_eq!(*second.value(), 1);
+    });
+}
+
+#[test]
+fn max_in_flight_bounds_every_wait_which_resolves_to_a_guard() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::builder().max_in_flight(1).build();
+    // the keys are kept in different shards, so that holding a guard into one never blocks
+    // locking the other
+    let first = String::from("Kronstadt");
+    let second = (0..).map(|i| format!("Kronstadt {}", i))
+        .find(|key| map.shard_index(key) != map.shard_index(&first))
+        .unwrap();
+    map.insert(first.clone(), 1921);
+    map.insert(second.clone(), 1917);
+
+    task::block_on(async {
+        let held = map.wait_mut(&first[..]).await.unwrap();
+
+        let mut eq = Box::pin(map.wait_eq(second.clone()));
+        assert!(poll_fn(|ctx| Poll::Ready(eq.as_mut().poll(ctx).is_pending())).await);
+        let keys = [&second[..]];
+        let mut any = Box::pin(map.wait_first(&keys));
+        assert!(poll_fn(|ctx| Poll::Ready(any.as_mut().poll(ctx).is_pending())).await);
+        drop(any);
+
+        drop(held);
+        let held = eq.await.unwrap();
+        let mut mutable = Box::pin(map.wait_mut(&first[..]));
+        assert!(poll_fn(|ctx| Poll::Ready(mutable.as_mut().poll(ctx).is_pending())).await);
+
+        drop(held);
+        assert_eq!(*mutable.await.unwrap().value(), 1921);
+    });
+}
+
+#[test]
+fn insert_wake_n_holds_back_newer_waiters() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let mut waiters = vec![];
+    for _ in 0..3 {
+        let map = map.clone();
+        waiters.push(task::spawn(async move {
+            map.wait("Rudolf Rocker").await.map(|r| *r.value())
+        }));
+        task::block_on(task::sleep(Duration::from_millis(20)));
+    }
+
+    assert_eq!(map.insert_wake_n(String::from("Rudolf Rocker"), 0, 2), (None, 2));
+    let newest = waiters.pop().unwrap();
+    for waiter in waiters {
+        assert_eq!(task::block_on(waiter), Some(0));
+    }
+
+    // the newest waiter only sees the key once it is filled again
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    map.insert(String::from("Rudolf Rocker"), 1);
+    assert_eq!(task::block_on(newest), Some(1));
+}
+
+#[test]
+fn insert_wake_n_wakes_held_back_waiters_on_remove() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+
+    let mut waiters = vec![];
+    for _ in 0..3 {
+        let map = map.clone();
+        waiters.push(task::spawn(async move {
+            map.wait("Lucía Sánchez Saornil").await.map(|r| *r.value())
+        }));
+        task::block_on(task::sleep(Duration::from_millis(20)));
+    }
+
+    assert_eq!(map.insert_wake_n(String::from("Lucía Sánchez Saornil"), 1895, 1), (None, 1));
+    let held_back = waiters.split_off(1);
+    assert_eq!(task::block_on(waiters.pop().unwrap()), Some(1895));
+
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    assert_eq!(map.remove("Lucía Sánchez Saornil"), Some(1895));
+    for waiter in held_back {
+        assert_eq!(task::block_on(waiter), None);
+    }
+}
+
+#[test]
+fn refs_debug_print_their_pair() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    map.insert(String::from("Ricardo Flores Magón"), 1873);
+
+    let magon = map.get("Ricardo Flores Magón").unwrap();
+    assert_eq!(format!("{:?}", magon), r#"Ref { key: "Ricardo Flores Magón", value: 1873 }"#);
+    drop(magon);
+    let magon = map.get_mut("Ricardo Flores Magón").unwrap();
+    assert_eq!(format!("{:?}", magon), r#"RefMut { key: "Ricardo Flores Magón", value: 1873 }"#);
+}
+
+#[test]
+fn get_skips_keys_waiting_to_be_filled() {
+    let map: WaitMap<String, i32> = WaitMap::new();
+    let _wait_fut = map.wait("Librado Rivera");
+
+    assert!(map.contains_waiting("Librado Rivera"));
+    assert!(map.get("Librado Rivera").is_none());
+    assert!(map.get_mut("Librado Rivera").is_none());
+
+    map.insert(String::from("Librado Rivera"), 1864);
+    assert_eq!(*map.get("Librado Rivera").unwrap(), 1864);
+}
+
+#[test]
+fn cancel_handle_cancels_a_parked_wait() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    let (wait, _) = map.reserve_wait("Ba Jin");
+    let mut wait = Box::pin(wait);
+
+    task::block_on(async {
+        assert!(poll_fn(|ctx| Poll::Ready(wait.as_mut().poll(ctx).is_pending())).await);
+        let handle = wait.cancel_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            handle.cancel();
+        });
+        assert!(wait.await.is_none());
+    });
+    assert!(map.contains_waiting("Ba Jin"));
+}
+
+#[test]
+fn compact_all_reclaims_orphaned_placeholders() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    for i in 0..3 {
+        drop(map.wait(&format!("Jean Grave {}", i)[..]));
+    }
+
+    task::block_on(async {
+        let mut waiting = Box::pin(map.wait("Jean Grave"));
+        assert!(poll_fn(|ctx| Poll::Ready(waiting.as_mut().poll(ctx).is_pending())).await);
+
+        assert_eq!(map.compact_all(), 3);
+        assert_eq!(map.compact_all(), 0);
+        assert_eq!(map.iter_waiting().count(), 1);
+
+        map.insert(String::from("Jean Grave"), 1854);
+        assert_eq!(*waiting.await.unwrap().value(), 1854);
+    });
+}
+
+#[test]
+fn unpolled_wait_survives_another_waiter_being_dropped() {
+    use std::future::{poll_fn, Future};
+    use std::task::Poll;
+
+    let map: WaitMap<String, i32> = WaitMap::new();
+    let unpolled = map.wait("Lucy Parsons");
+    let unpolled_mut = map.wait_mut("Lucy Parsons");
+    let unpolled_take = map.wait_take("Lucy Parsons");
+
+    task::block_on(async {
+        let mut polled = Box::pin(map.wait("Lucy Parsons"));
+        assert!(poll_fn(|ctx| Poll::Ready(polled.as_mut().poll(ctx).is_pending())).await);
+        drop(polled);
+
+        assert_eq!(map.compact_all(), 0);
+        map.insert(String::from("Lucy Parsons"), 1853);
+        assert_eq!(*unpolled.await.unwrap().value(), 1853);
+        assert_eq!(*unpolled_mut.await.unwrap().value(), 1853);
+        assert_eq!(unpolled_take.await, Some((String::from("Lucy Parsons"), 1853)));
+    });
+}
+
+#[test]
+fn wait_removed_resolves_once_the_key_is_gone() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    map.insert(String::from("Nestor Makhno"), 1888);
+    map.insert(String::from("Voline"), 1882);
+
+    let waiters: Vec<_> = ["Nestor Makhno", "Voline"].iter().copied().map(|name| {
+        let map = map.clone();
+        task::spawn(async move { map.wait_removed(name).await })
+    }).collect();
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    assert_eq!(map.remove("Nestor Makhno"), Some(1888));
+    map.cancel_all();
+    let results: Vec<_> = waiters.into_iter().map(task::block_on).collect();
+    assert_eq!(results, vec![true, false]);
+
+    assert!(task::block_on(map.wait_removed("Nestor Makhno")));
+}
+
+#[test]
+fn wait_eq_works_with_tuple_keys() {
+    let map: Arc<WaitMap<(u32, u32), &str>> = Arc::new(WaitMap::new());
+
+    let waiter = {
+        let map = map.clone();
+        task::spawn(async move { map.wait_eq((1, 2)).await.map(|r| *r.value()) })
+    };
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    assert!(map.contains_waiting(&(1, 2)));
+
+    map.insert((1, 2), "Confederación Nacional del Trabajo");
+    assert_eq!(task::block_on(waiter), Some("Confederación Nacional del Trabajo"));
+    assert_eq!(map.get(&(1, 2)).map(|r| *r.value()), Some("Confederación Nacional del Trabajo"));
+}
+
+#[test]
+fn wait_while_async_resolves_to_none_when_removed_after_a_rejection() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    map.insert(String::from("Errico Malatesta"), 1);
+
+    let waiter = {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait_while_async("Errico Malatesta", |count| async move { count > 1 }).await
+        })
+    };
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    assert_eq!(map.remove("Errico Malatesta"), Some(1));
+    assert_eq!(task::block_on(waiter), None);
+}
+
+#[test]
+fn try_entry_keeps_the_shard_locked_until_dropped() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    let waiter = {
+        let map = map.clone();
+        task::spawn(async move { map.wait("Louise Michel").await.map(|r| *r.value()) })
+    };
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    let entry = map.try_entry(String::from("Louise Michel")).unwrap();
+    assert!(map.try_entry(String::from("Louise Michel")).is_none());
+    let mut value = entry.or_insert(1871);
+    *value += 1;
+    assert!(map.try_entry(String::from("Louise Michel")).is_none());
+    drop(value);
+    assert_eq!(task::block_on(waiter), Some(1872));
+
+    let value = map.try_entry(String::from("Élisée Reclus")).unwrap().or_insert(1830);
+    assert_eq!(value.key(), "Élisée Reclus");
+    drop(value);
+    assert_eq!(map.get("Élisée Reclus").map(|r| *r.value()), Some(1830));
+    assert_eq!(map.len(), 2);
+}
+
+#[test]
+fn wait_while_async_rechecks_replaced_values_until_cancelled() {
+    let map: Arc<WaitMap<String, i32>> = Arc::new(WaitMap::new());
+    map.insert(String::from("Ricardo Flores Magón"), 1);
+
+    let waiter = {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait_while_async("Ricardo Flores Magón", |count| async move { count > 2 }).await
+        })
+    };
+    task::block_on(task::sleep(Duration::from_millis(50)));
+
+    map.insert(String::from("Ricardo Flores Magón"), 2);
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    map.cancel_all();
+    assert_eq!(task::block_on(waiter), None);
+    assert_eq!(map.get("Ricardo Flores Magón").map(|r| *r.value()), Some(2));
+
+    let waiter = {
+        let map = map.clone();
+        task::spawn(async move {
+            map.wait_while_async("Ricardo Flores Magón", |count| async move { count > 2 }).await
+        })
+    };
+    task::block_on(task::sleep(Duration::from_millis(50)));
+    map.insert(String::from("Ricardo Flores Magón"), 3);
+    assert_eq!(task::block_on(waiter), Some(3));
+}